@@ -0,0 +1,521 @@
+use crate::{Command, InputFormat, Result};
+use anyhow::anyhow;
+use clap::{parser::ValueSource, ArgMatches};
+use log::info;
+use serde::Deserialize;
+use std::{cmp::Ordering, env, fs, path::PathBuf};
+
+/// Name of the project-local configuration file.
+const PROJECT_CONFIG: &str = "rdl.toml";
+
+/// Defaults for the fields of [`Render`](crate::Render).
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct RenderConfig {
+    collective: Option<String>,
+    cloud: Option<bool>,
+    progress: Option<bool>,
+    statistics: Option<u8>,
+    threads: Option<usize>,
+    jobs: Option<usize>,
+    max_inflight: Option<usize>,
+    retries: Option<usize>,
+    retry_delay: Option<f64>,
+    fail_fast: Option<bool>,
+    report: Option<String>,
+    skip_existing: Option<bool>,
+    dry_run: Option<bool>,
+    force_render: Option<bool>,
+    frames: Option<String>,
+    input_format: Option<String>,
+    remap: Option<Vec<String>>,
+    remap_file: Option<String>,
+}
+
+/// Defaults for the fields of [`Cat`](crate::Cat).
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct CatConfig {
+    binary: Option<bool>,
+    gzip: Option<bool>,
+    zstd: Option<bool>,
+    expand: Option<bool>,
+    output: Option<String>,
+    input_format: Option<String>,
+    remap: Option<Vec<String>>,
+    remap_file: Option<String>,
+}
+
+/// Defaults for the fields of [`Watch`](crate::Watch).
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct WatchConfig {
+    collective: Option<String>,
+    cloud: Option<bool>,
+    recursive: Option<bool>,
+    settle: Option<f64>,
+    sentinel: Option<String>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    input_format: Option<String>,
+    remap: Option<Vec<String>>,
+    remap_file: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    render: RenderConfig,
+    cat: CatConfig,
+    watch: WatchConfig,
+}
+
+/// Copies config values into `args` for every field not given on the command
+/// line.
+///
+/// `plain` fields are assigned as is, `optional` ones wrapped in `Some`.
+macro_rules! merge {
+    ($config:expr, $args:expr, $matches:expr;
+     plain: $($plain:ident),*;
+     optional: $($optional:ident),*) => {
+        $(
+            if let Some(value) = $config.$plain {
+                if !explicit($matches, stringify!($plain)) {
+                    $args.$plain = value;
+                }
+            }
+        )*
+        $(
+            if let Some(value) = $config.$optional {
+                if !explicit($matches, stringify!($optional)) {
+                    $args.$optional = Some(value);
+                }
+            }
+        )*
+    };
+}
+
+/// Returns `true` if the argument `id` was not set from its default value.
+fn explicit(matches: &ArgMatches, id: &str) -> bool {
+    matches
+        .value_source(id)
+        .is_some_and(|source| ValueSource::DefaultValue != source)
+}
+
+/// Fills in the fields of `command` that were not given on the command line
+/// from the configuration files.
+///
+/// The user configuration at `$XDG_CONFIG_HOME/rdl/config.toml` is read
+/// first, then the first `rdl.toml` found in the current directory or any of
+/// its parents. Values from the latter win. If `--profile` is given, the
+/// `[profile.<name>]` tables of both files are layered on top.
+pub fn apply_config(command: Command, matches: &ArgMatches) -> Result<Command> {
+    let Some((_, matches)) = matches.subcommand() else {
+        return Ok(command);
+    };
+
+    let profile = match &command {
+        Command::Render(args) => args.config.profile.clone(),
+        Command::Cat(args) => args.config.profile.clone(),
+        Command::Watch(args) => args.config.profile.clone(),
+        _ => return Ok(command),
+    };
+
+    apply(command, load(profile.as_deref())?, matches)
+}
+
+/// Fills in the fields of `command` that `matches`, the matches of its
+/// subcommand, do not set explicitly from `config`.
+fn apply(command: Command, config: Config, matches: &ArgMatches) -> Result<Command> {
+    Ok(match command {
+        Command::Render(mut args) => {
+            let config = config.render;
+            check_input_format(config.input_format.as_deref())?;
+
+            merge!(config, args, matches;
+                plain: cloud, progress, statistics, max_inflight, retries, fail_fast,
+                skip_existing, dry_run, force_render;
+                optional: collective, threads, jobs, retry_delay, report, frames);
+            merge!(config, args.input, matches;
+                plain: remap;
+                optional: input_format, remap_file);
+
+            let (collective, cloud) = exclusive(
+                matches,
+                ("collective", args.collective.is_some()),
+                ("cloud", args.cloud),
+            )?;
+            if !collective {
+                args.collective = None;
+            }
+            args.cloud = cloud;
+            let (jobs, cloud) = exclusive(
+                matches,
+                ("jobs", args.jobs.is_some()),
+                ("cloud", args.cloud),
+            )?;
+            if !jobs {
+                args.jobs = None;
+            }
+            args.cloud = cloud;
+
+            Command::Render(args)
+        }
+        Command::Cat(mut args) => {
+            let config = config.cat;
+            check_input_format(config.input_format.as_deref())?;
+
+            merge!(config, args, matches;
+                plain: binary, gzip, zstd, expand;
+                optional: output);
+            merge!(config, args.input, matches;
+                plain: remap;
+                optional: input_format, remap_file);
+
+            (args.gzip, args.zstd) = exclusive(matches, ("gzip", args.gzip), ("zstd", args.zstd))?;
+
+            Command::Cat(args)
+        }
+        Command::Watch(mut args) => {
+            let config = config.watch;
+            check_input_format(config.input_format.as_deref())?;
+
+            merge!(config, args, matches;
+                plain: cloud, recursive, settle, include, exclude;
+                optional: collective, sentinel);
+            merge!(config, args.input, matches;
+                plain: remap;
+                optional: input_format, remap_file);
+
+            let (collective, cloud) = exclusive(
+                matches,
+                ("collective", args.collective.is_some()),
+                ("cloud", args.cloud),
+            )?;
+            if !collective {
+                args.collective = None;
+            }
+            args.cloud = cloud;
+
+            Command::Watch(args)
+        }
+        command => command,
+    })
+}
+
+/// Resolves two mutually exclusive options, given as `(id, is_set)`.
+///
+/// If both are set, the one from the command line wins over one from the
+/// environment, which wins over one from the config. Both from the same
+/// source is an error. Returns whether each option is still set.
+fn exclusive(
+    matches: &ArgMatches,
+    (a, a_set): (&str, bool),
+    (b, b_set): (&str, bool),
+) -> Result<(bool, bool)> {
+    if !(a_set && b_set) {
+        return Ok((a_set, b_set));
+    }
+
+    let source = |id| {
+        matches
+            .value_source(id)
+            .unwrap_or(ValueSource::DefaultValue)
+    };
+    match source(a).cmp(&source(b)) {
+        Ordering::Greater => Ok((true, false)),
+        Ordering::Less => Ok((false, true)),
+        Ordering::Equal => Err(anyhow!("--{a} and --{b} can not be used together")),
+    }
+}
+
+/// Rejects an `input-format` that `--input-format` would not accept.
+fn check_input_format(input_format: Option<&str>) -> Result<()> {
+    match input_format {
+        Some(name) if InputFormat::from_name(name).is_none() => Err(anyhow!(
+            "Invalid input-format '{name}' in config; expected 'nsi', 'binary' or 'lua'"
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn load(profile: Option<&str>) -> Result<Config> {
+    [user_config(), project_config()]
+        .into_iter()
+        .flatten()
+        .filter(|path| path.is_file())
+        .map(|path| {
+            info!("Reading config '{}'", path.display());
+            let table = fs::read_to_string(&path)?
+                .parse::<toml::Table>()
+                .map_err(|e| anyhow!("Could not parse '{}': {e}", path.display()))?;
+            Ok((path, table))
+        })
+        .collect::<Result<Vec<_>>>()
+        .and_then(|files| merge_files(&files, profile))
+}
+
+/// Merges the config `files`, later ones winning, and layers the
+/// `[profile.<name>]` tables of `profile` on top.
+fn merge_files(files: &[(PathBuf, toml::Table)], profile: Option<&str>) -> Result<Config> {
+    let mut merged = toml::Table::new();
+    let mut profile_found = false;
+
+    for (_, table) in files {
+        let mut table = table.clone();
+        table.remove("profile");
+        merge_tables(&mut merged, table);
+    }
+
+    if let Some(profile) = profile {
+        for (path, table) in files {
+            let Some(profiles) = table.get("profile") else {
+                continue;
+            };
+            let profiles = profiles
+                .as_table()
+                .ok_or_else(|| anyhow!("'{}': 'profile' must be a table", path.display()))?;
+
+            if let Some(table) = profiles.get(profile) {
+                let table = table.as_table().ok_or_else(|| {
+                    anyhow!("'{}': profile '{profile}' must be a table", path.display())
+                })?;
+                merge_tables(&mut merged, table.clone());
+                profile_found = true;
+            }
+        }
+
+        if !profile_found {
+            return Err(anyhow!("Profile '{profile}' not found in any config file"));
+        }
+    }
+
+    merged
+        .try_into()
+        .map_err(|e| anyhow!("Invalid config: {e}"))
+}
+
+/// Recursively merges `source` into `target`; values from `source` win.
+fn merge_tables(target: &mut toml::Table, source: toml::Table) {
+    for (key, value) in source {
+        match (target.get_mut(&key), value) {
+            (Some(toml::Value::Table(target)), toml::Value::Table(source)) => {
+                merge_tables(target, source)
+            }
+            (_, value) => {
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+fn user_config() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(dirs::config_dir)
+        .map(|dir| dir.join("rdl").join("config.toml"))
+}
+
+fn project_config() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cli;
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn config(files: &[&str], profile: Option<&str>) -> Result<Config> {
+        let files = files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| (PathBuf::from(format!("{i}.toml")), file.parse().unwrap()))
+            .collect::<Vec<_>>();
+
+        merge_files(&files, profile)
+    }
+
+    fn apply_to(command_line: &[&str], file: &str) -> Command {
+        apply_matches(
+            Cli::command().try_get_matches_from(command_line).unwrap(),
+            file,
+        )
+    }
+
+    fn apply_matches(matches: ArgMatches, file: &str) -> Command {
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+
+        apply(
+            cli.command,
+            config(&[file], None).unwrap(),
+            matches.subcommand().unwrap().1,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn layers_files_and_profiles() {
+        let files = [
+            "[render]\nretries = 1\nthreads = 2\n[profile.farm.render]\nretries = 10\n",
+            "[render]\nthreads = 4\n[profile.farm.render]\njobs = 3\n",
+        ];
+
+        let render = config(&files, None).unwrap().render;
+        assert_eq!(
+            (Some(1), Some(4), None),
+            (render.retries, render.threads, render.jobs)
+        );
+
+        let render = config(&files, Some("farm")).unwrap().render;
+        assert_eq!(
+            (Some(10), Some(4), Some(3)),
+            (render.retries, render.threads, render.jobs)
+        );
+
+        assert_eq!(
+            "Profile 'local' not found in any config file",
+            config(&files, Some("local")).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(config(&["[cat]\nthreads = 4\n"], None)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Invalid config: "));
+    }
+
+    #[test]
+    fn command_line_and_environment_win() {
+        // Tests run in parallel, so read `--threads` from a variable only this
+        // test sets.
+        env::set_var("RDL_TEST_CONFIG_THREADS", "4");
+        let matches = Cli::command()
+            .mut_subcommand("render", |render| {
+                render.mut_arg("threads", |threads| threads.env("RDL_TEST_CONFIG_THREADS"))
+            })
+            .try_get_matches_from(["rdl", "render", "--frames", "2", "a.nsi"])
+            .unwrap();
+
+        let Command::Render(args) = apply_matches(
+            matches,
+            "[render]\nretries = 5\nthreads = 8\nframes = \"1-10\"\n",
+        ) else {
+            panic!("not a render command");
+        };
+        assert_eq!(5, args.retries);
+        assert_eq!(Some(4), args.threads);
+        assert_eq!(Some("2".into()), args.frames);
+    }
+
+    #[test]
+    fn reads_input_format() {
+        let Command::Cat(args) =
+            apply_to(&["rdl", "cat", "a.nsi"], "[cat]\ninput-format = \"lua\"\n")
+        else {
+            panic!("not a cat command");
+        };
+        assert_eq!(Some("lua".into()), args.input.input_format);
+
+        let matches = Cli::command()
+            .try_get_matches_from(["rdl", "cat", "a.nsi"])
+            .unwrap();
+        assert_eq!(
+            "Invalid input-format 'rib' in config; expected 'nsi', 'binary' or 'lua'",
+            apply(
+                Command::Cat(args),
+                config(&["[cat]\ninput-format = \"rib\"\n"], None).unwrap(),
+                matches.subcommand().unwrap().1,
+            )
+            .map(|_| ())
+            .unwrap_err()
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn drops_exclusive_options() {
+        let Command::Render(args) = apply_to(
+            &["rdl", "render", "--cloud", "a.nsi"],
+            "[render]\ncollective = \"farm\"\n",
+        ) else {
+            panic!("not a render command");
+        };
+        assert_eq!((None, true), (args.collective, args.cloud));
+
+        let Command::Cat(args) =
+            apply_to(&["rdl", "cat", "--zstd", "a.nsi"], "[cat]\ngzip = true\n")
+        else {
+            panic!("not a cat command");
+        };
+        assert_eq!((false, true), (args.gzip, args.zstd));
+    }
+
+    #[test]
+    fn command_line_wins_over_environment_for_exclusive_options() {
+        // Tests run in parallel, so read the options from variables only this
+        // test sets.
+        env::set_var("RDL_TEST_EXCLUSIVE_COLLECTIVE", "farm");
+        env::set_var("RDL_TEST_EXCLUSIVE_JOBS", "4");
+        env::set_var("RDL_TEST_EXCLUSIVE_CLOUD", "false");
+        env::set_var("RDL_TEST_EXCLUSIVE_GZIP", "true");
+        let cli = || {
+            Cli::command()
+                .mut_subcommand("render", |render| {
+                    render
+                        .mut_arg("collective", |arg| arg.env("RDL_TEST_EXCLUSIVE_COLLECTIVE"))
+                        .mut_arg("jobs", |arg| arg.env("RDL_TEST_EXCLUSIVE_JOBS"))
+                        .mut_arg("cloud", |arg| arg.env("RDL_TEST_EXCLUSIVE_CLOUD"))
+                })
+                .mut_subcommand("cat", |cat| {
+                    cat.mut_arg("gzip", |arg| arg.env("RDL_TEST_EXCLUSIVE_GZIP"))
+                })
+        };
+
+        let matches = cli()
+            .try_get_matches_from(["rdl", "render", "--cloud", "a.nsi"])
+            .unwrap();
+        let Command::Render(args) = apply_matches(matches, "") else {
+            panic!("not a render command");
+        };
+        assert_eq!((None, None, true), (args.collective, args.jobs, args.cloud));
+
+        // `RDL_CLOUD=false` does not conflict.
+        let matches = cli()
+            .try_get_matches_from(["rdl", "render", "a.nsi"])
+            .unwrap();
+        let Command::Render(args) = apply_matches(matches, "") else {
+            panic!("not a render command");
+        };
+        assert_eq!(
+            (Some("farm".into()), Some(4), false),
+            (args.collective, args.jobs, args.cloud)
+        );
+
+        let matches = cli()
+            .try_get_matches_from(["rdl", "cat", "--zstd", "a.nsi"])
+            .unwrap();
+        let Command::Cat(args) = apply_matches(matches, "") else {
+            panic!("not a cat command");
+        };
+        assert_eq!((false, true), (args.gzip, args.zstd));
+
+        let matches = cli()
+            .try_get_matches_from(["rdl", "cat", "--gzip", "--zstd", "a.nsi"])
+            .unwrap();
+        let cat = Cli::from_arg_matches(&matches).unwrap().command;
+        assert_eq!(
+            "--gzip and --zstd can not be used together",
+            apply(cat, Config::default(), matches.subcommand().unwrap().1)
+                .map(|_| ())
+                .unwrap_err()
+                .to_string()
+        );
+    }
+}
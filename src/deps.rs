@@ -0,0 +1,343 @@
+use crate::{
+    is_texture,
+    stream::{self, Arg, Command, Statement},
+    Deps, Result,
+};
+use anyhow::anyhow;
+use log::{info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt,
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DependencyKind {
+    Archive,
+    Lua,
+    Procedural,
+    Shader,
+    Texture,
+}
+
+impl fmt::Display for DependencyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            DependencyKind::Archive => "archive",
+            DependencyKind::Lua => "lua",
+            DependencyKind::Procedural => "procedural",
+            DependencyKind::Shader => "shader",
+            DependencyKind::Texture => "texture",
+        })
+    }
+}
+
+/// An external file a scene references.
+#[derive(Clone, Debug)]
+pub struct Dependency {
+    pub kind: DependencyKind,
+    /// Where the file was found or, if it is missing, where it was expected.
+    pub resolved: PathBuf,
+    pub exists: bool,
+    /// The stream that references the file.
+    pub referenced_by: PathBuf,
+}
+
+pub fn deps(args: Deps) -> Result<()> {
+    let mut missing = 0;
+
+    for file_name in &args.file {
+        for dependency in dependencies(Path::new(file_name))? {
+            if !dependency.exists {
+                missing += 1;
+            } else if args.missing {
+                continue;
+            }
+
+            if dependency.exists {
+                println!("{:<12}{}", dependency.kind, dependency.resolved.display());
+            } else {
+                println!(
+                    "{:<12}{}  [missing, referenced by {}]",
+                    dependency.kind,
+                    dependency.resolved.display(),
+                    dependency.referenced_by.display()
+                );
+            }
+        }
+    }
+
+    if 0 < missing {
+        Err(anyhow!("{missing} dependencies are missing"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Collects all external files referenced by the stream at `path`.
+///
+/// Archives that are NSI streams are followed recursively.
+pub fn dependencies(path: &Path) -> Result<Vec<Dependency>> {
+    let mut dependencies = Vec::new();
+    let mut visited = HashSet::new();
+
+    collect(path, &mut dependencies, &mut visited)?;
+
+    Ok(dependencies)
+}
+
+fn collect(
+    path: &Path,
+    dependencies: &mut Vec<Dependency>,
+    visited: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    if !visited.insert(path.canonicalize().unwrap_or_else(|_| path.to_path_buf())) {
+        return Ok(());
+    }
+
+    info!("Scanning '{}'", path.display());
+
+    let statements = stream::read_file(path)?;
+    let start = dependencies.len();
+
+    for (kind, file_name) in references(&statements) {
+        let resolved = resolve(path, kind, &file_name);
+        let exists = resolved.exists();
+
+        if !dependencies
+            .iter()
+            .any(|d| d.kind == kind && d.resolved == resolved)
+        {
+            dependencies.push(Dependency {
+                kind,
+                resolved,
+                exists,
+                referenced_by: path.to_path_buf(),
+            });
+        }
+    }
+
+    let archives = dependencies[start..]
+        .iter()
+        .filter(|d| DependencyKind::Archive == d.kind && d.exists)
+        .map(|d| d.resolved.clone())
+        .collect::<Vec<_>>();
+
+    for archive in archives {
+        if let Err(error) = collect(&archive, dependencies, visited) {
+            warn!("{error}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the file references in a single stream, in stream order.
+pub fn references(statements: &[Statement]) -> Vec<(DependencyKind, String)> {
+    let mut references = Vec::new();
+    let mut node_types = HashMap::<&str, &str>::new();
+
+    for Statement { command, .. } in statements {
+        match command {
+            Command::Create {
+                handle, node_type, ..
+            } => {
+                node_types.insert(handle, node_type);
+            }
+            Command::Evaluate { args } => {
+                references.extend(procedural_reference(args));
+            }
+            Command::SetAttribute { handle, args }
+            | Command::SetAttributeAtTime { handle, args, .. } => {
+                let node_type = node_types.get(handle.as_str()).copied();
+                match node_type {
+                    Some("procedural") => references.extend(procedural_reference(args)),
+                    Some("shader") => references.extend(
+                        args.iter()
+                            .filter(|arg| "shaderfilename" == arg.name)
+                            .filter_map(|arg| arg.as_str())
+                            .map(|s| (DependencyKind::Shader, s.to_string())),
+                    ),
+                    _ => (),
+                }
+
+                // Output images are written by the render, see
+                // `output_images()`.
+                references.extend(
+                    args.iter()
+                        .filter(|arg| "shaderfilename" != arg.name)
+                        .filter(|arg| !is_output_image(node_type, arg))
+                        .flat_map(|arg| arg.strings())
+                        .filter(|s| is_texture(s))
+                        .map(|s| (DependencyKind::Texture, s.to_string())),
+                );
+            }
+            _ => (),
+        }
+    }
+
+    references
+}
+
+/// Returns `true` if `arg` is the `imagefilename` of an `outputdriver` node.
+pub fn is_output_image(node_type: Option<&str>, arg: &Arg) -> bool {
+    Some("outputdriver") == node_type && "imagefilename" == arg.name
+}
+
+/// Finds the `imagefilename`s of `outputdriver` nodes, in stream order.
+pub fn output_images(statements: &[Statement]) -> Vec<String> {
+    let mut images = Vec::<String>::new();
+    let mut node_types = HashMap::<&str, &str>::new();
+
+    for Statement { command, .. } in statements {
+        match command {
+            Command::Create {
+                handle, node_type, ..
+            } => {
+                node_types.insert(handle, node_type);
+            }
+            Command::SetAttribute { handle, args }
+            | Command::SetAttributeAtTime { handle, args, .. }
+                if Some(&"outputdriver") == node_types.get(handle.as_str()) =>
+            {
+                for image in args
+                    .iter()
+                    .filter(|arg| "imagefilename" == arg.name)
+                    .filter_map(|arg| arg.as_str())
+                {
+                    if !images.iter().any(|i| i == image) {
+                        images.push(image.to_string());
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    images
+}
+
+/// Reads the `type` & `filename` arguments of an `Evaluate` call or a
+/// `procedural` node.
+fn procedural_reference(args: &[Arg]) -> Option<(DependencyKind, String)> {
+    let file_name = args.iter().find(|arg| "filename" == arg.name)?.as_str()?;
+
+    let kind = match args
+        .iter()
+        .find(|arg| "type" == arg.name)
+        .and_then(|arg| arg.as_str())
+    {
+        Some("lua") => DependencyKind::Lua,
+        Some("dynamiclibrary") => DependencyKind::Procedural,
+        Some(_) => DependencyKind::Archive,
+        None if file_name.ends_with(".lua") => DependencyKind::Lua,
+        None => DependencyKind::Archive,
+    };
+
+    Some((kind, file_name.to_string()))
+}
+
+/// Resolves `file_name` relative to the stream referencing it, falling back
+/// to the current directory and, for shaders, the 3Delight installation.
+pub fn resolve(referenced_by: &Path, kind: DependencyKind, file_name: &str) -> PathBuf {
+    let path = PathBuf::from(file_name);
+    if path.is_absolute() {
+        return path;
+    }
+
+    let mut candidates = Vec::with_capacity(3);
+    if let Some(parent) = referenced_by.parent() {
+        candidates.push(parent.join(&path));
+    }
+    candidates.push(path.clone());
+    if DependencyKind::Shader == kind {
+        if let Ok(delight) = env::var("DELIGHT") {
+            candidates.push(Path::new(&delight).join("osl").join(&path));
+        }
+    }
+
+    candidates
+        .iter()
+        .find(|candidate| candidate.exists())
+        .cloned()
+        .unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, process};
+
+    const SCENE: &str = r#"Create "s" "shader"
+SetAttribute "s"
+  "shaderfilename" "string" 1 "dlPrincipled.oso"
+  "i_color" "string" 1 "textures/wood.tdl"
+  "label" "string" 1 "not/a/file"
+Create "p" "procedural"
+SetAttribute "p" "type" "string" 1 "dynamiclibrary" "filename" "string" 1 "fur.so"
+Evaluate "filename" "string" 1 "sub/archive.nsi" "type" "string" 1 "apistream"
+Evaluate "filename" "string" 1 "crowd.lua"
+Evaluate "filename" "string" 1 "crowd2.LUA" "type" "string" 1 "lua"
+Create "driver" "outputdriver"
+SetAttribute "driver" "imagefilename" "string" 1 "beauty.exr"
+SetAttributeAtTime "driver" 1 "imagefilename" "string" 1 "beauty.exr"
+Create "driver2" "outputdriver"
+SetAttribute "driver2" "imagefilename" "string" 1 "albedo.exr"
+"#;
+
+    #[test]
+    fn finds_references() {
+        let statements = stream::parse(SCENE).unwrap();
+
+        assert_eq!(
+            vec![
+                (DependencyKind::Shader, "dlPrincipled.oso".to_string()),
+                (DependencyKind::Texture, "textures/wood.tdl".into()),
+                (DependencyKind::Procedural, "fur.so".into()),
+                (DependencyKind::Archive, "sub/archive.nsi".into()),
+                (DependencyKind::Lua, "crowd.lua".into()),
+                (DependencyKind::Lua, "crowd2.LUA".into()),
+            ],
+            references(&statements)
+        );
+        assert_eq!(vec!["beauty.exr", "albedo.exr"], output_images(&statements));
+    }
+
+    #[test]
+    fn follows_archives() {
+        let root = env::temp_dir().join(format!("rdl-deps-{}", process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("scene.nsi"), SCENE).unwrap();
+        fs::write(
+            root.join("sub/archive.nsi"),
+            "Evaluate \"filename\" \"string\" 1 \"../scene.nsi\"\n\
+             Create \"s\" \"shader\"\n\
+             SetAttribute \"s\" \"shaderfilename\" \"string\" 1 \"hair.oso\"\n",
+        )
+        .unwrap();
+        fs::write(root.join("sub/hair.oso"), "").unwrap();
+
+        let dependencies = dependencies(&root.join("scene.nsi"));
+        let _ = fs::remove_dir_all(&root);
+
+        let dependencies = dependencies
+            .unwrap()
+            .into_iter()
+            .map(|d| {
+                let resolved = d.resolved.strip_prefix(&root).unwrap_or(&d.resolved);
+                (d.kind, resolved.to_string_lossy().to_string(), d.exists)
+            })
+            .collect::<Vec<_>>();
+
+        assert!(dependencies.contains(&(DependencyKind::Archive, "sub/archive.nsi".into(), true)));
+        assert!(dependencies.contains(&(DependencyKind::Shader, "sub/hair.oso".into(), true)));
+        assert!(dependencies.contains(&(
+            DependencyKind::Texture,
+            "textures/wood.tdl".into(),
+            false
+        )));
+        // The archive's reference back to the scene is listed but not
+        // scanned again.
+        assert!(dependencies.contains(&(DependencyKind::Archive, "sub/../scene.nsi".into(), true)));
+    }
+}
@@ -0,0 +1,481 @@
+use crate::{
+    stream::{self, Arg, Command, Statement, Value},
+    Diff, Result,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error, fmt, io,
+    path::Path,
+};
+
+#[derive(Default)]
+struct Node {
+    node_type: String,
+    /// Keyed by name, or by `name@time` for time samples.
+    attributes: BTreeMap<String, Arg>,
+}
+
+/// The node graph an NSI stream describes.
+#[derive(Default)]
+struct Scene {
+    nodes: BTreeMap<String, Node>,
+    /// `(from, from_attr, to, to_attr)`
+    connections: BTreeSet<(String, String, String, String)>,
+}
+
+impl Scene {
+    fn new(statements: &[Statement]) -> Self {
+        let mut scene = Scene::default();
+
+        for Statement { command, .. } in statements {
+            match command {
+                Command::Create {
+                    handle, node_type, ..
+                } => {
+                    scene.nodes.insert(
+                        handle.clone(),
+                        Node {
+                            node_type: node_type.clone(),
+                            ..Default::default()
+                        },
+                    );
+                }
+                Command::Delete { handle, .. } => {
+                    scene.nodes.remove(handle);
+                    scene
+                        .connections
+                        .retain(|(from, _, to, _)| from != handle && to != handle);
+                }
+                Command::SetAttribute { handle, args } => {
+                    let node = scene.nodes.entry(handle.clone()).or_default();
+                    for arg in args {
+                        node.attributes.insert(arg.name.clone(), arg.clone());
+                    }
+                }
+                Command::SetAttributeAtTime { handle, time, args } => {
+                    let node = scene.nodes.entry(handle.clone()).or_default();
+                    for arg in args {
+                        node.attributes
+                            .insert(format!("{}@{time}", arg.name), arg.clone());
+                    }
+                }
+                Command::DeleteAttribute { handle, name } => {
+                    if let Some(node) = scene.nodes.get_mut(handle) {
+                        node.attributes
+                            .retain(|key, _| key != name && !key.starts_with(&format!("{name}@")));
+                    }
+                }
+                Command::Connect {
+                    from,
+                    from_attr,
+                    to,
+                    to_attr,
+                    ..
+                } => {
+                    scene.connections.insert((
+                        from.clone(),
+                        from_attr.clone(),
+                        to.clone(),
+                        to_attr.clone(),
+                    ));
+                }
+                Command::Disconnect {
+                    from,
+                    from_attr,
+                    to,
+                    to_attr,
+                } => {
+                    scene.connections.remove(&(
+                        from.clone(),
+                        from_attr.clone(),
+                        to.clone(),
+                        to_attr.clone(),
+                    ));
+                }
+                Command::Evaluate { .. } | Command::RenderControl { .. } => (),
+            }
+        }
+
+        scene
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct NodeChange {
+    pub handle: String,
+    pub node_type: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TypeChange {
+    pub handle: String,
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttributeChange {
+    pub handle: String,
+    pub attribute: String,
+    /// The old value in ASCII NSI notation; `None` if the attribute was added.
+    pub old: Option<String>,
+    /// The new value in ASCII NSI notation; `None` if the attribute was
+    /// removed.
+    pub new: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Connection {
+    pub from: String,
+    pub from_attr: String,
+    pub to: String,
+    pub to_attr: String,
+}
+
+/// Semantic differences between two NSI streams.
+#[derive(Debug, Default, Serialize)]
+pub struct SceneDiff {
+    pub added_nodes: Vec<NodeChange>,
+    pub removed_nodes: Vec<NodeChange>,
+    pub changed_node_types: Vec<TypeChange>,
+    pub changed_attributes: Vec<AttributeChange>,
+    pub added_connections: Vec<Connection>,
+    pub removed_connections: Vec<Connection>,
+}
+
+impl SceneDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_nodes.is_empty()
+            && self.removed_nodes.is_empty()
+            && self.changed_node_types.is_empty()
+            && self.changed_attributes.is_empty()
+            && self.added_connections.is_empty()
+            && self.removed_connections.is_empty()
+    }
+}
+
+/// Errors of [`diff()`] that map to the exit codes of `diff(1)`.
+#[derive(Debug)]
+pub enum DiffError {
+    /// The streams differ.
+    Differ { old: String, new: String },
+    /// The streams could not be compared.
+    Failed(anyhow::Error),
+}
+
+impl DiffError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            DiffError::Differ { .. } => 1,
+            DiffError::Failed(_) => 2,
+        }
+    }
+}
+
+impl fmt::Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffError::Differ { old, new } => write!(f, "'{old}' and '{new}' differ"),
+            DiffError::Failed(error) => write!(f, "{error:#}"),
+        }
+    }
+}
+
+impl error::Error for DiffError {}
+
+pub fn diff(args: Diff) -> Result<()> {
+    let diff = compare(&args).map_err(DiffError::Failed)?;
+
+    if diff.is_empty() {
+        Ok(())
+    } else {
+        Err(DiffError::Differ {
+            old: args.old,
+            new: args.new,
+        }
+        .into())
+    }
+}
+
+fn compare(args: &Diff) -> Result<SceneDiff> {
+    let old = stream::read_file(Path::new(&args.old))?;
+    let new = stream::read_file(Path::new(&args.new))?;
+
+    let diff = diff_statements(&old, &new, args.tolerance);
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+    } else {
+        write_diff(&mut io::stdout().lock(), &diff)?;
+    }
+
+    Ok(diff)
+}
+
+fn write_diff(writer: &mut impl io::Write, diff: &SceneDiff) -> io::Result<()> {
+    for node in &diff.removed_nodes {
+        writeln!(writer, "- node \"{}\" ({})", node.handle, node.node_type)?;
+    }
+    for node in &diff.added_nodes {
+        writeln!(writer, "+ node \"{}\" ({})", node.handle, node.node_type)?;
+    }
+    for change in &diff.changed_node_types {
+        writeln!(
+            writer,
+            "~ node \"{}\" type {} ➞ {}",
+            change.handle, change.old, change.new
+        )?;
+    }
+    for change in &diff.changed_attributes {
+        match (&change.old, &change.new) {
+            (Some(old), Some(new)) => {
+                writeln!(writer, "~ \"{}\".\"{}\"", change.handle, change.attribute)?;
+                writeln!(writer, "    - {old}")?;
+                writeln!(writer, "    + {new}")?;
+            }
+            (Some(old), None) => writeln!(
+                writer,
+                "- \"{}\".\"{}\" {old}",
+                change.handle, change.attribute
+            )?,
+            (None, Some(new)) => writeln!(
+                writer,
+                "+ \"{}\".\"{}\" {new}",
+                change.handle, change.attribute
+            )?,
+            (None, None) => (),
+        }
+    }
+    for (sign, connections) in [
+        ('-', &diff.removed_connections),
+        ('+', &diff.added_connections),
+    ] {
+        for c in connections {
+            writeln!(
+                writer,
+                "{sign} connection \"{}\".\"{}\" ➞ \"{}\".\"{}\"",
+                c.from, c.from_attr, c.to, c.to_attr
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Compares the node graphs of two parsed streams.
+///
+/// Floating point values are considered equal if they differ by no more than
+/// `tolerance`.
+pub fn diff_statements(old: &[Statement], new: &[Statement], tolerance: f64) -> SceneDiff {
+    let old = Scene::new(old);
+    let new = Scene::new(new);
+    let mut diff = SceneDiff::default();
+
+    for (handle, old_node) in &old.nodes {
+        let Some(new_node) = new.nodes.get(handle) else {
+            diff.removed_nodes.push(NodeChange {
+                handle: handle.clone(),
+                node_type: old_node.node_type.clone(),
+            });
+            continue;
+        };
+
+        if old_node.node_type != new_node.node_type {
+            diff.changed_node_types.push(TypeChange {
+                handle: handle.clone(),
+                old: old_node.node_type.clone(),
+                new: new_node.node_type.clone(),
+            });
+        }
+
+        let keys = old_node
+            .attributes
+            .keys()
+            .chain(new_node.attributes.keys())
+            .collect::<BTreeSet<_>>();
+
+        for key in keys {
+            let old_arg = old_node.attributes.get(key);
+            let new_arg = new_node.attributes.get(key);
+
+            let equal = match (old_arg, new_arg) {
+                (Some(old_arg), Some(new_arg)) => args_equal(old_arg, new_arg, tolerance),
+                _ => false,
+            };
+
+            if !equal {
+                diff.changed_attributes.push(AttributeChange {
+                    handle: handle.clone(),
+                    attribute: key.clone(),
+                    old: old_arg.map(|arg| arg.to_string()),
+                    new: new_arg.map(|arg| arg.to_string()),
+                });
+            }
+        }
+    }
+
+    for (handle, new_node) in &new.nodes {
+        if !old.nodes.contains_key(handle) {
+            diff.added_nodes.push(NodeChange {
+                handle: handle.clone(),
+                node_type: new_node.node_type.clone(),
+            });
+        }
+    }
+
+    let connection =
+        |(from, from_attr, to, to_attr): &(String, String, String, String)| Connection {
+            from: from.clone(),
+            from_attr: from_attr.clone(),
+            to: to.clone(),
+            to_attr: to_attr.clone(),
+        };
+    diff.removed_connections = old
+        .connections
+        .difference(&new.connections)
+        .map(connection)
+        .collect();
+    diff.added_connections = new
+        .connections
+        .difference(&old.connections)
+        .map(connection)
+        .collect();
+
+    diff
+}
+
+fn args_equal(a: &Arg, b: &Arg, tolerance: f64) -> bool {
+    if a.data_type != b.data_type || a.array_len != b.array_len || a.count != b.count {
+        return false;
+    }
+
+    let close = |a: f64, b: f64| (a - b).abs() <= tolerance;
+
+    match (&a.value, &b.value) {
+        (Value::Integers(a), Value::Integers(b)) => a == b,
+        (Value::Strings(a), Value::Strings(b)) => a == b,
+        (Value::Floats(a), Value::Floats(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| close(*a as _, *b as _))
+        }
+        (Value::Doubles(a), Value::Doubles(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| close(*a, *b))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = r#"Create "xform" "transform"
+Create "mesh1" "mesh"
+SetAttribute "mesh1"
+  "nvertices" "int" 1 3
+  "P" "point" 3 [ 0 0 0  1 0 0  1 1 0 ]
+SetAttributeAtTime "xform" 0
+  "transformationmatrix" "doublematrix" 1 [
+    1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1 ]
+Connect "mesh1" "" "xform" "objects"
+Connect "xform" "" ".root" "objects"
+"#;
+
+    fn output(old: &str, new: &str, tolerance: f64) -> String {
+        let diff = diff_statements(
+            &stream::parse(old).unwrap(),
+            &stream::parse(new).unwrap(),
+            tolerance,
+        );
+
+        let mut output = Vec::new();
+        write_diff(&mut output, &diff).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn ignores_order() {
+        let new = r#"Create "mesh1" "mesh"
+SetAttribute "mesh1" "P" "point" 3 [ 0 0 0  1 0 0  1 1 0 ]
+Create "xform" "transform"
+Connect "xform" "" ".root" "objects"
+SetAttributeAtTime "xform" 0
+  "transformationmatrix" "doublematrix" 1 [
+    1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1 ]
+Connect "mesh1" "" "xform" "objects"
+SetAttribute "mesh1" "nvertices" "int" 1 3
+"#;
+
+        assert!(diff_statements(
+            &stream::parse(OLD).unwrap(),
+            &stream::parse(new).unwrap(),
+            0.0
+        )
+        .is_empty());
+        assert_eq!("", output(OLD, new, 0.0));
+    }
+
+    #[test]
+    fn reports_changes() {
+        let new = r#"Create "xform" "transform"
+Create "mesh1" "curves"
+SetAttribute "mesh1"
+  "nvertices" "int" 1 3
+  "P" "point" 3 [ 0 0 0  1 0 0  1 2 0 ]
+  "width" "float" 1 0.1
+SetAttributeAtTime "xform" 1
+  "transformationmatrix" "doublematrix" 1 [
+    1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1 ]
+Create "mesh2" "mesh"
+Connect "mesh2" "" "xform" "objects"
+Connect "xform" "" ".root" "objects"
+"#;
+
+        assert_eq!(
+            r#"+ node "mesh2" (mesh)
+~ node "mesh1" type mesh ➞ curves
+~ "mesh1"."P"
+    - "P" "point" 3 [ 0 0 0 1 0 0 1 1 0 ]
+    + "P" "point" 3 [ 0 0 0 1 0 0 1 2 0 ]
++ "mesh1"."width" "width" "float" 1 0.1
+- "xform"."transformationmatrix@0" "transformationmatrix" "doublematrix" 1 [ 1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1 ]
++ "xform"."transformationmatrix@1" "transformationmatrix" "doublematrix" 1 [ 1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1 ]
+- connection "mesh1"."" ➞ "xform"."objects"
++ connection "mesh2"."" ➞ "xform"."objects"
+"#,
+            output(OLD, new, 0.0)
+        );
+    }
+
+    #[test]
+    fn compares_floats_with_tolerance() {
+        let new = OLD.replace("1 1 0 ]", "1 1.001 0 ]");
+
+        assert!(!output(OLD, &new, 0.0).is_empty());
+        assert_eq!("", output(OLD, &new, 0.01));
+    }
+
+    #[test]
+    fn exits_like_diff() {
+        let file = std::env::temp_dir().join(format!("rdl-diff-{}.nsi", std::process::id()));
+        std::fs::write(&file, OLD).unwrap();
+        let exit_code = |new: &str| {
+            diff(Diff {
+                old: file.to_string_lossy().to_string(),
+                new: new.to_string(),
+                tolerance: 0.0,
+                json: false,
+            })
+            .map_err(|error| error.downcast_ref::<DiffError>().map(DiffError::exit_code))
+        };
+
+        assert_eq!(Ok(()), exit_code(file.to_str().unwrap()));
+        assert_eq!(Err(Some(2)), exit_code("missing.nsi"));
+        let new = file.with_extension("new.nsi");
+        std::fs::write(&new, OLD.replace("mesh1", "mesh2")).unwrap();
+        assert_eq!(Err(Some(1)), exit_code(new.to_str().unwrap()));
+
+        std::fs::remove_file(file).unwrap();
+        std::fs::remove_file(new).unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+use crate::{stream, Result};
+use anyhow::anyhow;
+use log::{debug, warn};
+use std::{
+    cmp::Ordering,
+    env, fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, IsTerminal, Read},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Characters that make a `FILE` argument a glob pattern.
+const GLOB_CHARS: [char; 3] = ['*', '?', '['];
+
+/// Number of (decompressed) bytes read to detect the format of a file.
+const SNIFF_LEN: u64 = 4096;
+
+/// The format of an input file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    /// ASCII NSI stream.
+    Nsi,
+    /// Binary NSI stream.
+    BinaryNsi,
+    /// Lua NSI file.
+    Lua,
+}
+
+impl InputFormat {
+    /// Parses a value of `--input-format`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nsi" => Some(InputFormat::Nsi),
+            "binary" => Some(InputFormat::BinaryNsi),
+            "lua" => Some(InputFormat::Lua),
+            _ => None,
+        }
+    }
+
+    /// Returns the format `forced` with `--input-format` or else the one
+    /// detected for `file_name`.
+    pub fn of(file_name: &str, forced: Option<&str>) -> Self {
+        forced
+            .and_then(Self::from_name)
+            .unwrap_or_else(|| Self::detect(file_name))
+    }
+
+    /// Detects the format of `file_name` from its extension and contents.
+    ///
+    /// Only `.lua` files are Lua, comparing the extension ignoring case and a
+    /// `.gz`, `.zst` or `.zstd` suffix. All others are NSI; the
+    /// (decompressed) contents tell binary from ASCII.
+    pub fn detect(file_name: &str) -> Self {
+        let name = file_name.to_ascii_lowercase();
+        let name = [".gz", ".zst", ".zstd"]
+            .iter()
+            .find_map(|suffix| name.strip_suffix(suffix))
+            .unwrap_or(&name);
+
+        if name.ends_with(".lua") {
+            return InputFormat::Lua;
+        }
+
+        // Unreadable files are left for the reader to report.
+        let Ok(prefix) = read_prefix(file_name) else {
+            return InputFormat::Nsi;
+        };
+
+        if stream::is_binary(&prefix) {
+            InputFormat::BinaryNsi
+        } else {
+            InputFormat::Nsi
+        }
+    }
+
+    /// The `type` to pass to `NSIEvaluate` for this format.
+    pub fn evaluate_type(self) -> &'static str {
+        match self {
+            InputFormat::Lua => "lua",
+            InputFormat::Nsi | InputFormat::BinaryNsi => "apistream",
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InputFormat::Nsi => "ASCII NSI",
+            InputFormat::BinaryNsi => "binary NSI",
+            InputFormat::Lua => "Lua",
+        })
+    }
+}
+
+/// Reads the first [`SNIFF_LEN`] bytes of `file_name`, decompressing them if
+/// needed.
+fn read_prefix(file_name: &str) -> io::Result<Vec<u8>> {
+    let mut file = BufReader::new(File::open(file_name)?);
+    let compression = stream::Compression::detect(file.fill_buf()?);
+
+    let reader: Box<dyn Read> = match compression {
+        Some(compression) => compression.decoder(file)?,
+        None => Box::new(file),
+    };
+
+    let mut prefix = Vec::new();
+    reader.take(SNIFF_LEN).read_to_end(&mut prefix)?;
+
+    Ok(prefix)
+}
+
+/// The `FILE` name that stands for stdin.
+pub const STDIN: &str = "-";
+
+/// A copy of stdin in a temporary file.
+///
+/// The file is deleted when this is dropped.
+#[derive(Debug)]
+pub struct StdinFile {
+    path: PathBuf,
+}
+
+impl StdinFile {
+    /// Reads all of stdin into a temporary file.
+    pub fn read() -> Result<Self> {
+        let mut stdin = io::stdin().lock();
+        if stdin.is_terminal() {
+            return Err(anyhow!(
+                "No FILE given and stdin is a terminal; pipe an NSI stream into rdl or use '-h'"
+            ));
+        }
+
+        let mut buffer = Vec::new();
+        stdin
+            .read_to_end(&mut buffer)
+            .map_err(|e| anyhow!("Could not read stdin: {e}"))?;
+
+        // No extension; stdin is read as NSI unless told otherwise.
+        let path = env::temp_dir().join(format!("rdl-stdin-{}", process::id()));
+        fs::write(&path, buffer)?;
+
+        debug!("Read stdin into '{}'", path.display());
+
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap_or_default()
+    }
+}
+
+impl Drop for StdinFile {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.path) {
+            warn!("Could not remove '{}': {error}", self.path.display());
+        }
+    }
+}
+
+/// Returns `names` or, if that is empty, [`STDIN`].
+pub fn or_stdin(names: &[String]) -> Vec<String> {
+    if names.is_empty() {
+        vec![STDIN.to_string()]
+    } else {
+        names.to_vec()
+    }
+}
+
+/// Reads stdin into a [`StdinFile`] if any of `names` is [`STDIN`].
+pub fn read_stdin_if_named(names: &[String]) -> Result<Option<StdinFile>> {
+    names
+        .iter()
+        .any(|name| STDIN == name)
+        .then(StdinFile::read)
+        .transpose()
+}
+
+/// Expands the glob patterns in `names`.
+///
+/// Patterns use `*`, `?`, `[…]` and `**` for any number of folders, e.g.
+/// `shots/**/*.nsi`. They are expanded the same way on all platforms,
+/// case-sensitively and without help from the shell. The matches of each
+/// pattern are sorted naturally, i.e. `f.2.nsi` before `f.10.nsi`. Hidden
+/// files and folders – e.g. rdl's own temporary copies – are never matched.
+///
+/// Names that are not patterns or that name an existing file are passed
+/// through as is, as are patterns without matches and [`STDIN`].
+pub fn expand_globs(names: &[String]) -> Result<Vec<String>> {
+    let options = glob::MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: true,
+    };
+
+    let mut expanded = Vec::with_capacity(names.len());
+
+    for name in names {
+        if !name.contains(GLOB_CHARS) || Path::new(name).exists() {
+            expanded.push(name.clone());
+            continue;
+        }
+
+        let mut matches = glob::glob_with(name, options)
+            .map_err(|e| anyhow!("Invalid glob pattern '{name}': {e}"))?
+            .filter_map(|entry| entry.ok())
+            .filter(|path| path.is_file())
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        if matches.is_empty() {
+            debug!("'{name}' matches no files");
+            expanded.push(name.clone());
+        } else {
+            matches.sort_by(|a, b| natural_cmp(a, b));
+            expanded.extend(matches);
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// Compares strings treating runs of digits as numbers, e.g. `f.9.nsi` <
+/// `f.10.nsi`.
+///
+/// Strings that only differ in leading zeros are ordered by plain byte
+/// comparison to keep the order total.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_rest = a;
+    let mut b_rest = b;
+
+    loop {
+        let (a_chunk, a_digits) = next_chunk(a_rest);
+        let (b_chunk, b_digits) = next_chunk(b_rest);
+
+        let ordering = match (a_chunk.is_empty(), b_chunk.is_empty()) {
+            (true, true) => return a.cmp(b),
+            (true, false) => return Ordering::Less,
+            (false, true) => return Ordering::Greater,
+            _ if a_digits && b_digits => {
+                let a_number = a_chunk.trim_start_matches('0');
+                let b_number = b_chunk.trim_start_matches('0');
+                a_number
+                    .len()
+                    .cmp(&b_number.len())
+                    .then_with(|| a_number.cmp(b_number))
+            }
+            _ => a_chunk.cmp(b_chunk),
+        };
+
+        if Ordering::Equal != ordering {
+            return ordering;
+        }
+
+        a_rest = &a_rest[a_chunk.len()..];
+        b_rest = &b_rest[b_chunk.len()..];
+    }
+}
+
+/// Splits off the leading run of digits or non-digits of `s`.
+///
+/// Returns the run and whether it consists of digits.
+fn next_chunk(s: &str) -> (&str, bool) {
+    let digits = s.starts_with(|c: char| c.is_ascii_digit());
+    let len = s
+        .find(|c: char| c.is_ascii_digit() != digits)
+        .unwrap_or(s.len());
+    (&s[..len], digits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_naturally() {
+        let mut names = vec![
+            "f.10.nsi",
+            "f.9.nsi",
+            "f.009.nsi",
+            "f.09.nsi",
+            "g.1.nsi",
+            "f.nsi",
+            "f.100.nsi",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+
+        assert_eq!(
+            vec![
+                "f.009.nsi",
+                "f.09.nsi",
+                "f.9.nsi",
+                "f.10.nsi",
+                "f.100.nsi",
+                "f.nsi",
+                "g.1.nsi"
+            ],
+            names
+        );
+        assert_eq!(
+            Ordering::Less,
+            natural_cmp("shot2/f.1.nsi", "shot10/f.1.nsi")
+        );
+        assert_eq!(Ordering::Equal, natural_cmp("f.01.nsi", "f.01.nsi"));
+    }
+
+    #[test]
+    fn detects_formats() {
+        let root = env::temp_dir().join(format!("rdl-detect-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let file = |name: &str, contents: &[u8]| {
+            let path = root.join(name);
+            fs::write(&path, contents).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+
+        let formats = [
+            file("a.lua", b"nsi.Create()"),
+            file("a.LUA", b"nsi.Create()"),
+            file(
+                "a.lua.gz",
+                &stream::Compression::Gzip.compress(b"-- Lua").unwrap(),
+            ),
+            file("a.txt", b"print(1)"),
+            file("a.nsi", b"Create \"a\" \"mesh\""),
+            file(
+                "a.nsi.gz",
+                &stream::Compression::Gzip.compress(b"\x01\x00").unwrap(),
+            ),
+            "missing.nsi".into(),
+            "missing.Lua.zstd".into(),
+        ]
+        .map(|file_name| InputFormat::detect(&file_name));
+        let _ = fs::remove_dir_all(&root);
+
+        use InputFormat::*;
+        assert_eq!([Lua, Lua, Lua, Nsi, Nsi, BinaryNsi, Nsi, Lua], formats);
+        assert_eq!(BinaryNsi, InputFormat::of("a.lua", Some("binary")));
+    }
+
+    #[test]
+    fn expands_globs() {
+        let root = env::temp_dir().join(format!("rdl-globs-{}", process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        for name in [
+            "f.10.nsi",
+            "f.2.nsi",
+            ".f.2.nsi.rdl-remap-1-0.nsi",
+            "sub/g.nsi",
+        ] {
+            fs::write(root.join(name), "").unwrap();
+        }
+        let glob = |pattern: &str| {
+            expand_globs(&[format!("{}/{pattern}", root.display())])
+                .unwrap()
+                .into_iter()
+                .map(|path| path[root.to_string_lossy().len() + 1..].replace('\\', "/"))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["f.2.nsi", "f.10.nsi"], glob("*.nsi"));
+        assert_eq!(vec!["f.2.nsi", "f.10.nsi", "sub/g.nsi"], glob("**/*.nsi"));
+        assert_eq!(vec!["*.lua"], glob("*.lua"));
+        assert_eq!(vec!["-"], expand_globs(&["-".into()]).unwrap());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,633 @@
+use crate::{
+    references, resolve,
+    stream::{self, Command, Span, Statement, Type},
+    DependencyKind, InputFormat, Lint, Result,
+};
+use anyhow::anyhow;
+use log::warn;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+const ROOT: &str = ".root";
+const GLOBAL: &str = ".global";
+
+const CAMERA_TYPES: &[&str] = &[
+    "perspectivecamera",
+    "orthographiccamera",
+    "fisheyecamera",
+    "cylindricalcamera",
+    "sphericalcamera",
+];
+
+/// Types of well-known attributes, by node type.
+const ATTRIBUTE_TYPES: &[(&str, &str, Type, Option<usize>)] = &[
+    ("mesh", "P", Type::Point, None),
+    ("mesh", "nvertices", Type::Integer, None),
+    ("curves", "P", Type::Point, None),
+    ("curves", "nvertices", Type::Integer, None),
+    ("particles", "P", Type::Point, None),
+    ("particles", "width", Type::Float, None),
+    (
+        "transform",
+        "transformationmatrix",
+        Type::DoubleMatrix,
+        None,
+    ),
+    ("shader", "shaderfilename", Type::String, None),
+    ("screen", "resolution", Type::Integer, Some(2)),
+    ("screen", "oversampling", Type::Integer, None),
+    ("outputlayer", "variablename", Type::String, None),
+    ("outputlayer", "layertype", Type::String, None),
+    ("outputlayer", "scalarformat", Type::String, None),
+    ("outputdriver", "drivername", Type::String, None),
+    ("outputdriver", "imagefilename", Type::String, None),
+];
+
+/// Attributes that nodes can be connected to, by node type.
+const CONNECTION_TARGETS: &[(&str, &[&str])] = &[
+    ("transform", &["objects", "geometryattributes"]),
+    ("camera", &["screens"]),
+    ("screen", &["outputlayers"]),
+    ("outputlayer", &["outputdrivers"]),
+    (
+        "attributes",
+        &["surfaceshader", "displacementshader", "volumeshader"],
+    ),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// `None` for problems concerning the scene as a whole.
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+struct Node {
+    node_type: String,
+    span: Span,
+}
+
+fn is_special(handle: &str) -> bool {
+    ROOT == handle || GLOBAL == handle
+}
+
+fn node_type<'a>(nodes: &'a HashMap<String, Node>, handle: &str) -> Option<&'a str> {
+    if ROOT == handle {
+        Some("transform")
+    } else {
+        nodes.get(handle).map(|node| node.node_type.as_str())
+    }
+}
+
+pub fn lint(args: Lint) -> Result<()> {
+    let mut errors = 0;
+    let mut warnings = 0;
+
+    for file_name in &args.file {
+        if InputFormat::Lua == InputFormat::detect(file_name) {
+            warn!("Skipping Lua NSI file '{file_name}'");
+            continue;
+        }
+
+        let statements = match read_inlined(Path::new(file_name), &mut Vec::new()) {
+            Ok(statements) => statements,
+            Err(error) => {
+                errors += 1;
+                println!("{file_name}: {}: {error}", Severity::Error);
+                continue;
+            }
+        };
+
+        for diagnostic in lint_statements(&statements, args.archive) {
+            match diagnostic.severity {
+                Severity::Error => errors += 1,
+                Severity::Warning => warnings += 1,
+            }
+
+            match diagnostic.span {
+                Some(span) => println!(
+                    "{file_name}:{span}: {}: {}",
+                    diagnostic.severity, diagnostic.message
+                ),
+                None => println!(
+                    "{file_name}: {}: {}",
+                    diagnostic.severity, diagnostic.message
+                ),
+            }
+        }
+    }
+
+    if 0 < errors {
+        Err(anyhow!("{errors} error(s), {warnings} warning(s) found"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Reads the stream at `path` with the NSI archives it evaluates inlined, as
+/// 3Delight sees it.
+///
+/// Statements from an archive get the span of the `Evaluate` that evaluates
+/// it. `Evaluate`s of archives that can not be read are kept.
+fn read_inlined(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Vec<Statement>> {
+    let mut inlined = Vec::new();
+
+    stack.push(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+
+    for statement in stream::read_file(path)? {
+        let archive = match references(std::slice::from_ref(&statement)).pop() {
+            Some((DependencyKind::Archive, file_name)) => {
+                resolve(path, DependencyKind::Archive, &file_name)
+            }
+            _ => {
+                inlined.push(statement);
+                continue;
+            }
+        };
+
+        let canonical = archive.canonicalize().unwrap_or_else(|_| archive.clone());
+        if stack.contains(&canonical) {
+            warn!("'{}' evaluates itself", archive.display());
+            continue;
+        }
+
+        match read_inlined(&archive, stack) {
+            Ok(statements) => inlined.extend(statements.into_iter().map(|archived| Statement {
+                command: archived.command,
+                span: statement.span,
+            })),
+            Err(error) => {
+                warn!("{error}");
+                inlined.push(statement);
+            }
+        }
+    }
+
+    stack.pop();
+
+    Ok(inlined)
+}
+
+/// Checks a parsed stream for problems.
+///
+/// If `archive` is set, checks that only make sense for a complete scene are
+/// skipped.
+///
+/// Nodes may be created by the archives an `Evaluate` reads. Once the stream
+/// evaluates one, references to undefined nodes are only warnings.
+pub fn lint_statements(statements: &[Statement], archive: bool) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut undefined = Severity::Error;
+    let mut nodes = HashMap::<String, Node>::new();
+    let mut attribute_types = HashMap::<(String, String), (Type, Option<usize>)>::new();
+    // (from, to, to_attr)
+    let mut connections = HashSet::<(String, String, String)>::new();
+
+    let mut report = |severity, span, message| {
+        diagnostics.push(Diagnostic {
+            severity,
+            span: Some(span),
+            message,
+        })
+    };
+
+    for Statement { command, span } in statements {
+        let span = *span;
+
+        match command {
+            Command::Create {
+                handle, node_type, ..
+            } => {
+                if let Some(previous) = nodes.get(handle) {
+                    report(
+                        Severity::Error,
+                        span,
+                        format!(
+                            "Node \"{handle}\" already created at {} as \"{}\"",
+                            previous.span, previous.node_type
+                        ),
+                    );
+                } else {
+                    nodes.insert(
+                        handle.clone(),
+                        Node {
+                            node_type: node_type.clone(),
+                            span,
+                        },
+                    );
+                }
+            }
+            Command::Delete { handle, .. } => {
+                if nodes.remove(handle).is_none() {
+                    report(
+                        undefined,
+                        span,
+                        format!("Delete of undefined node \"{handle}\""),
+                    );
+                }
+                connections.retain(|(from, to, _)| from != handle && to != handle);
+            }
+            Command::SetAttribute { handle, args }
+            | Command::SetAttributeAtTime { handle, args, .. } => {
+                if !is_special(handle) && !nodes.contains_key(handle) {
+                    report(
+                        undefined,
+                        span,
+                        format!("Attribute set on undefined node \"{handle}\""),
+                    );
+                    continue;
+                }
+
+                let node_type = node_type(&nodes, handle).unwrap_or_default();
+
+                for arg in args {
+                    let declared = (arg.data_type, arg.array_len);
+
+                    if let Some((_, _, data_type, array_len)) = ATTRIBUTE_TYPES
+                        .iter()
+                        .find(|(t, name, _, _)| *t == node_type && *name == arg.name)
+                    {
+                        if (*data_type, *array_len) != declared {
+                            report(
+                                Severity::Error,
+                                span,
+                                format!(
+                                    "Attribute \"{}\" of {node_type} \"{handle}\" must be of type \
+                                    {}, not {}",
+                                    arg.name,
+                                    type_name(*data_type, *array_len),
+                                    type_name(arg.data_type, arg.array_len),
+                                ),
+                            );
+                        }
+                    }
+
+                    match attribute_types.get(&(handle.clone(), arg.name.clone())) {
+                        Some(previous) if *previous != declared => report(
+                            Severity::Error,
+                            span,
+                            format!(
+                                "Attribute \"{}\" of \"{handle}\" was previously set as {}, now \
+                                as {}",
+                                arg.name,
+                                type_name(previous.0, previous.1),
+                                type_name(arg.data_type, arg.array_len),
+                            ),
+                        ),
+                        Some(_) => (),
+                        None => {
+                            attribute_types.insert((handle.clone(), arg.name.clone()), declared);
+                        }
+                    }
+                }
+            }
+            Command::DeleteAttribute { handle, .. } => {
+                if !is_special(handle) && !nodes.contains_key(handle) {
+                    report(
+                        undefined,
+                        span,
+                        format!("Attribute deleted on undefined node \"{handle}\""),
+                    );
+                }
+            }
+            Command::Connect {
+                from,
+                from_attr,
+                to,
+                to_attr,
+                ..
+            } => {
+                let mut dangling = false;
+                for handle in [from, to] {
+                    if !is_special(handle) && !nodes.contains_key(handle) {
+                        report(
+                            undefined,
+                            span,
+                            format!("Connection references undefined node \"{handle}\""),
+                        );
+                        dangling = true;
+                    }
+                }
+                if dangling {
+                    continue;
+                }
+
+                if from_attr.is_empty() {
+                    let to_type = node_type(&nodes, to).unwrap_or_default();
+                    let targets = CONNECTION_TARGETS
+                        .iter()
+                        .find(|(t, _)| {
+                            *t == to_type || ("camera" == *t && CAMERA_TYPES.contains(&to_type))
+                        })
+                        .map(|(_, targets)| *targets);
+
+                    if let Some(targets) = targets {
+                        if !targets.contains(&to_attr.as_str()) {
+                            report(
+                                Severity::Error,
+                                span,
+                                format!(
+                                    "{to_type} \"{to}\" has no attribute \"{to_attr}\" to connect \
+                                    to (expected one of: {})",
+                                    targets.join(", ")
+                                ),
+                            );
+                        }
+                    }
+                }
+
+                connections.insert((from.clone(), to.clone(), to_attr.clone()));
+            }
+            Command::Disconnect {
+                from, to, to_attr, ..
+            } => {
+                connections.retain(|c| !(&c.0 == from && &c.1 == to && &c.2 == to_attr));
+            }
+            Command::Evaluate { .. } => undefined = Severity::Warning,
+            Command::RenderControl { .. } => (),
+        }
+    }
+
+    if archive {
+        return diagnostics;
+    }
+
+    // Nodes that can not reach `.root` (or `.global`) are never rendered.
+    let mut reachable = HashSet::<&str>::from([ROOT, GLOBAL]);
+    let mut queue = VecDeque::from([ROOT, GLOBAL]);
+    while let Some(to) = queue.pop_front() {
+        for (from, _, _) in connections.iter().filter(|(_, t, _)| t == to) {
+            if reachable.insert(from.as_str()) {
+                queue.push_back(from);
+            }
+        }
+    }
+
+    let mut orphans = nodes
+        .iter()
+        .filter(|(handle, _)| !reachable.contains(handle.as_str()))
+        .collect::<Vec<_>>();
+    orphans.sort_by_key(|(_, node)| node.span.start);
+    for (handle, node) in orphans {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            span: Some(node.span),
+            message: format!(
+                "{} \"{handle}\" is never connected to {ROOT}",
+                node.node_type
+            ),
+        });
+    }
+
+    // The output chain: outputdriver → outputlayer → screen → camera.
+    let handles_of_type = |node_type: &str| {
+        let mut handles = nodes
+            .iter()
+            .filter(|(_, node)| node.node_type == node_type)
+            .collect::<Vec<_>>();
+        handles.sort_by_key(|(_, node)| node.span.start);
+        handles
+    };
+    let is_connected = |from_type: &dyn Fn(&str) -> bool, to: &str| {
+        connections
+            .iter()
+            .any(|(f, t, _)| t == to && node_type(&nodes, f).is_some_and(from_type))
+    };
+    let is_connected_to = |from: &str, to_type: &dyn Fn(&str) -> bool| {
+        connections
+            .iter()
+            .any(|(f, t, _)| f == from && node_type(&nodes, t).is_some_and(to_type))
+    };
+
+    let screens = handles_of_type("screen");
+    if screens.is_empty() {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            span: None,
+            message: "Scene has no screen".to_string(),
+        });
+    }
+    for (handle, node) in screens {
+        if !is_connected_to(handle, &|t| CAMERA_TYPES.contains(&t)) {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                span: Some(node.span),
+                message: format!("screen \"{handle}\" is not connected to a camera"),
+            });
+        }
+        if !is_connected(&|t| "outputlayer" == t, handle) {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                span: Some(node.span),
+                message: format!("screen \"{handle}\" has no outputlayer connected"),
+            });
+        }
+    }
+
+    for (handle, node) in handles_of_type("outputlayer") {
+        if !is_connected(&|t| "outputdriver" == t, handle) {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                span: Some(node.span),
+                message: format!("outputlayer \"{handle}\" has no outputdriver connected"),
+            });
+        }
+        if !is_connected_to(handle, &|t| "screen" == t) {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                span: Some(node.span),
+                message: format!("outputlayer \"{handle}\" is not connected to a screen"),
+            });
+        }
+    }
+
+    if handles_of_type("outputdriver").is_empty() {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            span: None,
+            message: "Scene has no outputdriver".to_string(),
+        });
+    }
+
+    diagnostics
+}
+
+fn type_name(data_type: Type, array_len: Option<usize>) -> String {
+    match array_len {
+        Some(len) => format!("{data_type}[{len}]"),
+        None => data_type.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    const SCENE: &str = r#"Create "camxform" "transform"
+Connect "camxform" "" ".root" "objects"
+Create "cam" "perspectivecamera"
+Connect "cam" "" "camxform" "objects"
+Create "screen" "screen"
+SetAttribute "screen" "resolution" "int[2]" 1 [ 640 480 ]
+Connect "screen" "" "cam" "screens"
+Create "beauty" "outputlayer"
+SetAttribute "beauty" "variablename" "string" 1 "Ci"
+Connect "beauty" "" "screen" "outputlayers"
+Create "driver" "outputdriver"
+SetAttribute "driver"
+  "drivername" "string" 1 "exr"
+  "imagefilename" "string" 1 "beauty.exr"
+Connect "driver" "" "beauty" "outputdrivers"
+Create "mesh1" "mesh"
+SetAttribute "mesh1"
+  "nvertices" "int" 1 4
+  "P" "point" 4 [ 0 0 0  1 0 0  1 1 0  0 1 0 ]
+Connect "mesh1" "" ".root" "objects"
+"#;
+
+    fn diagnostics(source: &str, archive: bool) -> Vec<String> {
+        lint_statements(&stream::parse(source).unwrap(), archive)
+            .into_iter()
+            .map(|diagnostic| match diagnostic.span {
+                Some(span) => format!("{span}: {}: {}", diagnostic.severity, diagnostic.message),
+                None => format!("{}: {}", diagnostic.severity, diagnostic.message),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn accepts_complete_scene() {
+        assert_eq!(Vec::<String>::new(), diagnostics(SCENE, false));
+    }
+
+    #[test]
+    fn reports_statement_errors() {
+        let source = r#"Create "m" "mesh"
+Create "m" "transform"
+SetAttribute "m" "P" "float" 1 0
+SetAttribute "m" "P" "point" 1 [ 0 0 0 ]
+SetAttribute "x" "P" "point" 1 [ 0 0 0 ]
+DeleteAttribute "x" "P"
+Create "t" "transform"
+Connect "m" "" "t" "object"
+Connect "m" "" "u" "objects"
+Delete "y"
+"#;
+
+        assert_eq!(
+            vec![
+                "2:1: error: Node \"m\" already created at 1:1 as \"mesh\"",
+                "3:1: error: Attribute \"P\" of mesh \"m\" must be of type point, not float",
+                "4:1: error: Attribute \"P\" of \"m\" was previously set as float, now as point",
+                "5:1: error: Attribute set on undefined node \"x\"",
+                "6:1: error: Attribute deleted on undefined node \"x\"",
+                "8:1: error: transform \"t\" has no attribute \"object\" to connect to (expected \
+                one of: objects, geometryattributes)",
+                "9:1: error: Connection references undefined node \"u\"",
+                "10:1: error: Delete of undefined node \"y\"",
+            ],
+            diagnostics(source, true)
+        );
+    }
+
+    #[test]
+    fn reports_scene_errors() {
+        // Drop the driver and connect the mesh to nothing.
+        let source = SCENE
+            .replace("Create \"driver\" \"outputdriver\"", "")
+            .replace("Connect \"mesh1\" \"\" \".root\" \"objects\"", "");
+
+        assert_eq!(
+            vec![
+                "12:1: error: Attribute set on undefined node \"driver\"",
+                "15:1: error: Connection references undefined node \"driver\"",
+                "16:1: warning: mesh \"mesh1\" is never connected to .root",
+                "8:1: error: outputlayer \"beauty\" has no outputdriver connected",
+                "error: Scene has no outputdriver",
+            ],
+            diagnostics(&source, false)
+        );
+    }
+
+    #[test]
+    fn skips_scene_checks_for_archives() {
+        let source = r#"Create "mesh1" "mesh""#;
+
+        assert_eq!(
+            vec![
+                "1:1: warning: mesh \"mesh1\" is never connected to .root",
+                "error: Scene has no screen",
+                "error: Scene has no outputdriver",
+            ],
+            diagnostics(source, false)
+        );
+        assert_eq!(Vec::<String>::new(), diagnostics(source, true));
+    }
+
+    #[test]
+    fn follows_archives() {
+        let root = env::temp_dir().join(format!("rdl-lint-{}", process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(
+            root.join("scene.nsi"),
+            "Evaluate \"filename\" \"string\" 1 \"sub/geo.nsi\"\n\
+             Connect \"mesh1\" \"\" \".root\" \"objects\"\n\
+             Evaluate \"filename\" \"string\" 1 \"missing.nsi\"\n\
+             Connect \"mesh2\" \"\" \".root\" \"objects\"\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("sub/geo.nsi"),
+            "Create \"mesh1\" \"mesh\"\nSetAttribute \"mesh1\" \"P\" \"float\" 1 0\n",
+        )
+        .unwrap();
+        fs::write(root.join("bad.nsi"), "Crate \"mesh1\" \"mesh\"\n").unwrap();
+
+        let statements = read_inlined(&root.join("scene.nsi"), &mut Vec::new()).unwrap();
+        let result = lint(Lint {
+            file: vec![
+                root.join("bad.nsi").to_string_lossy().into(),
+                root.join("scene.nsi").to_string_lossy().into(),
+            ],
+            archive: true,
+        });
+        let _ = fs::remove_dir_all(&root);
+
+        // Problems inside an archive are reported at the `Evaluate` of it.
+        assert_eq!(
+            vec![
+                "1:1: error: Attribute \"P\" of mesh \"mesh1\" must be of type point, not float",
+                "4:1: warning: Connection references undefined node \"mesh2\"",
+            ],
+            lint_statements(&statements, true)
+                .into_iter()
+                .map(|d| format!("{}: {}: {}", d.span.unwrap(), d.severity, d.message))
+                .collect::<Vec<_>>()
+        );
+        // The unparsable file counts as one error and does not stop the run.
+        assert_eq!(
+            "2 error(s), 1 warning(s) found",
+            result.unwrap_err().to_string()
+        );
+    }
+}
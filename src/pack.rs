@@ -0,0 +1,414 @@
+use crate::{
+    references, resolve,
+    stream::{self, Statement, Value},
+    DependencyKind, Pack, Result, Unpack,
+};
+use anyhow::anyhow;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+/// Name of the checksum file at the root of a bundle.
+///
+/// It uses the format of `sha256sum` so bundles can also be verified with
+/// standard tools.
+const MANIFEST: &str = "MANIFEST.sha256";
+
+/// Where files outside the scene's folder are stored inside a bundle.
+const EXTERNAL: &str = "external";
+
+struct Entry {
+    source: PathBuf,
+    bundle_path: String,
+    /// Rewritten stream contents; `None` if the file is copied verbatim.
+    contents: Option<Vec<u8>>,
+}
+
+pub fn pack(args: Pack) -> Result<()> {
+    let scene = Path::new(&args.file)
+        .canonicalize()
+        .map_err(|e| anyhow!("Could not find '{}': {e}", args.file))?;
+    let scene_dir = scene.parent().unwrap_or(Path::new("/")).to_path_buf();
+    let scene_name = scene
+        .file_name()
+        .ok_or_else(|| anyhow!("'{}' is not a file", args.file))?
+        .to_string_lossy()
+        .to_string();
+
+    let mut entries = Vec::new();
+    let mut bundle_paths = HashMap::<PathBuf, String>::from([(scene.clone(), scene_name.clone())]);
+    let mut streams = VecDeque::from([(scene, scene_name)]);
+
+    while let Some((source, bundle_path)) = streams.pop_front() {
+        info!("Packing '{}'", source.display());
+
+        let bytes = fs::read(&source)?;
+        let compression = stream::Compression::detect(&bytes);
+        // Only rdl's binary encoding can be written back; other binary
+        // streams are bundled as ASCII.
+        let binary = stream::has_magic(&stream::decompress(bytes)?);
+        let mut statements = stream::read_file(&source)?;
+
+        let mut rewrites = HashMap::<String, String>::new();
+
+        for (kind, file_name) in references(&statements) {
+            let resolved = resolve(&source, kind, &file_name);
+            let Ok(resolved) = resolved.canonicalize() else {
+                warn!(
+                    "'{file_name}' referenced by '{}' is missing and will not be packed",
+                    source.display()
+                );
+                continue;
+            };
+
+            let dependency_path = bundle_paths
+                .entry(resolved.clone())
+                .or_insert_with(|| {
+                    let dependency_path = bundle_path_of(&scene_dir, &resolved);
+
+                    match kind {
+                        DependencyKind::Archive => {
+                            streams.push_back((resolved.clone(), dependency_path.clone()))
+                        }
+                        _ => {
+                            if DependencyKind::Lua == kind {
+                                warn!(
+                                    "Paths inside Lua file '{}' are not rewritten",
+                                    resolved.display()
+                                );
+                            }
+                            entries.push(Entry {
+                                source: resolved.clone(),
+                                bundle_path: dependency_path.clone(),
+                                contents: None,
+                            })
+                        }
+                    }
+
+                    dependency_path
+                })
+                .clone();
+
+            rewrites.insert(file_name, dependency_path);
+        }
+
+        rewrite_paths(&mut statements, &rewrites);
+
+        let mut contents = Vec::new();
+        let commands = statements.iter().map(|statement| &statement.command);
+        if binary {
+            stream::write_binary(&mut contents, commands)?;
+        } else {
+            stream::write_ascii(&mut contents, commands)?;
+        }
+        // Keep the compression of the original.
+        if let Some(compression) = compression {
+            contents = compression.compress(&contents)?;
+        }
+
+        entries.push(Entry {
+            source,
+            bundle_path,
+            contents: Some(contents),
+        });
+    }
+
+    let output = File::create(&args.output)?;
+    if args.output.ends_with(".tar") {
+        write_bundle(output, &entries)?;
+    } else {
+        write_bundle(GzEncoder::new(output, Compression::default()), &entries)?.finish()?;
+    }
+
+    info!("Packed {} file(s) into '{}'", entries.len(), args.output);
+
+    Ok(())
+}
+
+/// Where a file ends up inside the bundle.
+///
+/// Files inside the scene's folder keep their relative location. All other
+/// files are stored under [`EXTERNAL`], mirroring their absolute path.
+fn bundle_path_of(scene_dir: &Path, path: &Path) -> String {
+    let relative = path
+        .strip_prefix(scene_dir)
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|_| {
+            Path::new(EXTERNAL).join(
+                path.components()
+                    .filter(|component| matches!(component, Component::Normal(_)))
+                    .collect::<PathBuf>(),
+            )
+        });
+
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Replaces string values that match a key of `rewrites`.
+fn rewrite_paths(statements: &mut [Statement], rewrites: &HashMap<String, String>) {
+    statements
+        .iter_mut()
+        .filter_map(|statement| statement.command.args_mut())
+        .flatten()
+        .for_each(|arg| {
+            if let Value::Strings(strings) = &mut arg.value {
+                for s in strings.iter_mut() {
+                    if let Some(new) = rewrites.get(s) {
+                        *s = new.clone();
+                    }
+                }
+            }
+        });
+}
+
+fn write_bundle<W: Write>(writer: W, entries: &[Entry]) -> Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    let mut manifest = String::new();
+
+    for entry in entries {
+        let digest = match &entry.contents {
+            Some(contents) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata(&fs::metadata(&entry.source)?);
+                header.set_size(contents.len() as _);
+                builder.append_data(&mut header, &entry.bundle_path, contents.as_slice())?;
+
+                Sha256::digest(contents)
+            }
+            None => {
+                builder.append_path_with_name(&entry.source, &entry.bundle_path)?;
+
+                let mut hasher = Sha256::new();
+                io::copy(&mut File::open(&entry.source)?, &mut hasher)?;
+                hasher.finalize()
+            }
+        };
+
+        manifest.push_str(&format!("{digest:x}  {}\n", entry.bundle_path));
+    }
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as _);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, MANIFEST, manifest.as_bytes())?;
+
+    Ok(builder.into_inner()?)
+}
+
+pub fn unpack(args: Unpack) -> Result<()> {
+    let output = match &args.output {
+        Some(output) => PathBuf::from(output),
+        None => {
+            let name = Path::new(&args.file)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let stem = name
+                .trim_end_matches(".gz")
+                .trim_end_matches(".tgz")
+                .trim_end_matches(".tar");
+            if stem.is_empty() || stem == name {
+                return Err(anyhow!(
+                    "Can not name a folder after '{}' as it does not end in .tar, .tgz or \
+                     .gz; use --output",
+                    args.file
+                ));
+            }
+            PathBuf::from(stem)
+        }
+    };
+
+    let mut file = BufReader::new(File::open(&args.file)?);
+    let gzipped = file.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+    let reader: Box<dyn Read> = if gzipped {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    info!("Unpacking '{}' into '{}'", args.file, output.display());
+    fs::create_dir_all(&output)
+        .map_err(|e| anyhow!("Could not create '{}': {e}", output.display()))?;
+    let mut extracted = Vec::new();
+    for entry in tar::Archive::new(reader).entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_file() {
+            extracted.push(entry.path()?.to_string_lossy().to_string());
+        }
+        entry.unpack_in(&output)?;
+    }
+
+    let manifest = fs::read_to_string(output.join(MANIFEST))
+        .map_err(|e| anyhow!("Bundle has no readable {MANIFEST}: {e}"))?;
+
+    let mut mismatches = 0;
+    let mut listed = HashSet::from([MANIFEST]);
+    for line in manifest.lines() {
+        let Some((digest, path)) = line.split_once("  ") else {
+            continue;
+        };
+        listed.insert(path);
+
+        let mut hasher = Sha256::new();
+        let matches = File::open(output.join(path))
+            .and_then(|mut file| io::copy(&mut file, &mut hasher))
+            .map(|_| format!("{:x}", hasher.finalize()) == digest)
+            .unwrap_or(false);
+
+        if !matches {
+            warn!("Checksum mismatch for '{path}'");
+            mismatches += 1;
+        }
+    }
+
+    for path in extracted
+        .iter()
+        .filter(|path| !listed.contains(path.as_str()))
+    {
+        warn!("'{path}' is not listed in {MANIFEST}");
+        mismatches += 1;
+    }
+
+    if 0 < mismatches {
+        Err(anyhow!("{mismatches} file(s) failed verification"))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn places_files_in_bundle() {
+        let scene_dir = Path::new("/show/shot");
+
+        assert_eq!(
+            "sub/a.nsi",
+            bundle_path_of(scene_dir, Path::new("/show/shot/sub/a.nsi"))
+        );
+        assert_eq!(
+            "external/show/textures/wood.tdl",
+            bundle_path_of(scene_dir, Path::new("/show/textures/wood.tdl"))
+        );
+    }
+
+    #[test]
+    fn rewrites_exact_paths() {
+        let mut statements =
+            stream::parse(r#"SetAttribute "s" "a" "string" 2 [ "/t/wood.tdl" "/t/wood.tdl.bak" ]"#)
+                .unwrap();
+        rewrite_paths(
+            &mut statements,
+            &HashMap::from([("/t/wood.tdl".into(), "external/t/wood.tdl".into())]),
+        );
+
+        assert_eq!(
+            Some(&Value::Strings(vec![
+                "external/t/wood.tdl".into(),
+                "/t/wood.tdl.bak".into()
+            ])),
+            statements[0].command.args_mut().map(|args| &args[0].value)
+        );
+    }
+
+    #[test]
+    fn round_trips_bundles() {
+        let root = env::temp_dir().join(format!("rdl-pack-{}", process::id()));
+        let textures = env::temp_dir().join(format!("rdl-pack-textures-{}", process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir_all(&textures).unwrap();
+        fs::write(textures.join("wood.tdl"), "wood").unwrap();
+        fs::write(
+            root.join("scene.nsi"),
+            "Evaluate \"filename\" \"string\" 1 \"sub/a.nsi\" \"type\" \"string\" 1 \"apistream\"\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("sub/a.nsi"),
+            format!(
+                "SetAttribute \"s\" \"i_color\" \"string\" 1 \"{}\"\n",
+                textures.join("wood.tdl").display()
+            ),
+        )
+        .unwrap();
+
+        let bundle = root.join("scene.tar");
+        let unpacked = root.join("unpacked");
+        pack(Pack {
+            file: root.join("scene.nsi").to_string_lossy().into(),
+            output: bundle.to_string_lossy().into(),
+        })
+        .unwrap();
+        let result = unpack(Unpack {
+            file: bundle.to_string_lossy().into(),
+            output: Some(unpacked.to_string_lossy().into()),
+        });
+        let archive = fs::read_to_string(unpacked.join("sub/a.nsi"));
+        let texture = textures.canonicalize().unwrap().join("wood.tdl");
+        let texture = unpacked.join(bundle_path_of(&root.canonicalize().unwrap(), &texture));
+        let texture = fs::read_to_string(texture);
+
+        let _ = fs::remove_dir_all(&root);
+        let _ = fs::remove_dir_all(&textures);
+
+        result.unwrap();
+        assert!(archive.unwrap().contains("\"external/"));
+        assert_eq!("wood", texture.unwrap());
+    }
+
+    #[test]
+    fn rejects_unlisted_files() {
+        let root = env::temp_dir().join(format!("rdl-unpack-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, contents) in [
+            (MANIFEST, format!("{:x}  scene.nsi\n", Sha256::digest(""))),
+            ("scene.nsi", String::new()),
+            ("extra.nsi", String::new()),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as _);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        fs::write(root.join("scene.tar"), builder.into_inner().unwrap()).unwrap();
+
+        let result = unpack(Unpack {
+            file: root.join("scene.tar").to_string_lossy().into(),
+            output: Some(root.join("scene").to_string_lossy().into()),
+        });
+        let _ = fs::remove_dir_all(&root);
+
+        assert_eq!(
+            "1 file(s) failed verification",
+            result.unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn requires_output_for_other_extensions() {
+        let error = unpack(Unpack {
+            file: "bundle.zip".into(),
+            output: None,
+        })
+        .unwrap_err();
+
+        assert!(error.to_string().ends_with("use --output"));
+    }
+}
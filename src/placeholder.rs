@@ -0,0 +1,414 @@
+use std::{fs, mem, path::Path};
+
+/// A part of a [`FramePattern`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    /// A frame number, padded to `width` with `fill`.
+    Frame {
+        width: usize,
+        fill: char,
+    },
+}
+
+/// A file name with frame number placeholders.
+///
+/// The supported styles are:
+///
+/// | Placeholder     | Frame 7          | Origin  |
+/// | --------------- | ---------------- | ------- |
+/// | `@`, `@4`       | `7`, `0007`      | `rdl`   |
+/// | `#`, `####`     | `7`, `0007`      | Nuke    |
+/// | `%d`, `%04d`    | `7`, `0007`      | printf  |
+/// | `$F`, `$F4`     | `7`, `0007`      | Houdini |
+/// | `${F}`, `${F4}` | `7`, `0007`      | Houdini |
+///
+/// Padding may have any number of digits and a name may contain more than
+/// one placeholder; all are replaced with the same frame. A literal `%` is
+/// written as `%%`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FramePattern {
+    parts: Vec<Part>,
+}
+
+impl FramePattern {
+    /// Parses `name`.
+    ///
+    /// Returns `None` if `name` contains no placeholder.
+    pub fn new(name: &str) -> Option<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = name;
+
+        while let Some(c) = rest.chars().next() {
+            match parse_placeholder(rest) {
+                Some((part, len)) => {
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                    rest = &rest[len..];
+                }
+                None => {
+                    // `%%` is an escaped `%`.
+                    let len = if rest.starts_with("%%") {
+                        2
+                    } else {
+                        c.len_utf8()
+                    };
+                    literal.push(c);
+                    rest = &rest[len..];
+                }
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        parts
+            .iter()
+            .any(|part| matches!(part, Part::Frame { .. }))
+            .then_some(Self { parts })
+    }
+
+    /// Returns the file name for `frame`.
+    pub fn format(&self, frame: isize) -> String {
+        self.parts.iter().map(|part| part.format(frame)).collect()
+    }
+
+    /// Finds the frames for which a file exists, in ascending order.
+    ///
+    /// Placeholders may also be part of folder names.
+    pub fn discover(&self) -> Vec<isize> {
+        let components = self.components();
+
+        // Folders up to the first placeholder are taken as is.
+        let literal = components
+            .iter()
+            .take_while(|component| {
+                component
+                    .iter()
+                    .all(|part| matches!(part, Part::Literal(_)))
+            })
+            .count();
+        let root = components[..literal]
+            .iter()
+            .map(|component| {
+                component
+                    .iter()
+                    .map(|part| part.format(0))
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let root = match root.as_str() {
+            // The pattern is relative or the root folder itself.
+            "" if literal == 0 => ".",
+            "" => "/",
+            root => root,
+        };
+
+        let mut frames = Vec::new();
+        discover(Path::new(root), &components[literal..], None, &mut frames);
+
+        frames.sort_unstable();
+        frames.dedup();
+        frames
+    }
+
+    /// Splits the pattern into path components.
+    fn components(&self) -> Vec<Vec<Part>> {
+        let mut components = vec![Vec::new()];
+
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => {
+                    for (index, piece) in literal.split(['/', '\\']).enumerate() {
+                        if 0 < index {
+                            components.push(Vec::new());
+                        }
+                        if !piece.is_empty() {
+                            components
+                                .last_mut()
+                                .unwrap()
+                                .push(Part::Literal(piece.to_string()));
+                        }
+                    }
+                }
+                part => components.last_mut().unwrap().push(part.clone()),
+            }
+        }
+
+        components
+    }
+}
+
+impl Part {
+    fn format(&self, frame: isize) -> String {
+        match self {
+            Part::Literal(literal) => literal.clone(),
+            Part::Frame { width, fill: '0' } => format!("{frame:0width$}"),
+            Part::Frame { width, .. } => format!("{frame:>width$}"),
+        }
+    }
+}
+
+/// Collects the frames of entries below `folder` matching `components`.
+///
+/// All placeholders of a path must match the same `frame`.
+fn discover(
+    folder: &Path,
+    components: &[Vec<Part>],
+    frame: Option<isize>,
+    frames: &mut Vec<isize>,
+) {
+    let Some((component, rest)) = components.split_first() else {
+        frames.extend(frame);
+        return;
+    };
+
+    // Skip empty components, e.g. from `foo//bar`.
+    if component.is_empty() {
+        return discover(folder, rest, frame, frames);
+    }
+
+    let Ok(entries) = fs::read_dir(folder) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if let Some(frame) = match_parts(component, &name, frame) {
+            if rest.is_empty() || entry.path().is_dir() {
+                discover(&entry.path(), rest, frame, frames);
+            }
+        }
+    }
+}
+
+/// Matches `name` against `parts`.
+///
+/// Returns the frame matched, which must equal `frame` if that is given.
+/// Frames only match if they are formatted exactly as `format()` would.
+fn match_parts(parts: &[Part], name: &str, frame: Option<isize>) -> Option<Option<isize>> {
+    match parts.split_first() {
+        None => name.is_empty().then_some(frame),
+        Some((Part::Literal(literal), rest)) => {
+            match_parts(rest, name.strip_prefix(literal.as_str())?, frame)
+        }
+        Some((part, rest)) => {
+            let max_len = name
+                .bytes()
+                .take_while(|&b| b.is_ascii_digit() || b'-' == b || b' ' == b)
+                .count();
+
+            // Try the longest number first.
+            (1..=max_len).rev().find_map(|len| {
+                let text = &name[..len];
+                let number = text.trim_start().parse::<isize>().ok()?;
+                if part.format(number) != text || frame.is_some_and(|frame| frame != number) {
+                    return None;
+                }
+                match_parts(rest, &name[len..], Some(number))
+            })
+        }
+    }
+}
+
+/// Formats `frames`, which must be sorted, as ranges, e.g. `1-3, 7, 9-12`.
+pub fn format_ranges(frames: &[isize]) -> String {
+    let mut ranges = Vec::<(isize, isize)>::new();
+
+    for &frame in frames {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == frame => *last = frame,
+            _ => ranges.push((frame, frame)),
+        }
+    }
+
+    ranges
+        .into_iter()
+        .map(|(first, last)| {
+            if first == last {
+                first.to_string()
+            } else {
+                format!("{first}-{last}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Returns the frames between the first and last of `frames`, which must be
+/// sorted, that are not in `frames`.
+pub fn gaps(frames: &[isize]) -> Vec<isize> {
+    frames
+        .windows(2)
+        .flat_map(|pair| pair[0] + 1..pair[1])
+        .collect()
+}
+
+/// Parses a placeholder at the start of `s`.
+///
+/// Returns the placeholder and its length in bytes.
+fn parse_placeholder(s: &str) -> Option<(Part, usize)> {
+    let zero = |width| Part::Frame { width, fill: '0' };
+
+    if let Some(rest) = s.strip_prefix('@') {
+        let (width, len) = leading_number(rest);
+        return Some((zero(width.unwrap_or(0)), 1 + len));
+    }
+
+    if s.starts_with('#') {
+        let len = s.bytes().take_while(|&b| b'#' == b).count();
+        return Some((zero(len), len));
+    }
+
+    if let Some(rest) = s.strip_prefix('%') {
+        // `%[0][width]d`
+        let (fill, rest, flag_len) = match rest.strip_prefix('0') {
+            Some(rest) => ('0', rest, 1),
+            None => (' ', rest, 0),
+        };
+        let (width, len) = leading_number(rest);
+        return rest[len..].starts_with('d').then(|| {
+            (
+                Part::Frame {
+                    width: width.unwrap_or(0),
+                    fill,
+                },
+                1 + flag_len + len + 1,
+            )
+        });
+    }
+
+    if let Some(rest) = s.strip_prefix("${F") {
+        let (width, len) = leading_number(rest);
+        return rest[len..]
+            .starts_with('}')
+            .then(|| (zero(width.unwrap_or(0)), 3 + len + 1));
+    }
+
+    if let Some(rest) = s.strip_prefix("$F") {
+        let (width, len) = leading_number(rest);
+        // Don't mistake variables like `$FOO` or `$F_END` for a frame.
+        return (!rest[len..].starts_with(|c: char| c.is_alphanumeric() || '_' == c))
+            .then(|| (zero(width.unwrap_or(0)), 2 + len));
+    }
+
+    None
+}
+
+/// Parses the decimal number at the start of `s`, if any.
+///
+/// Returns the number and its length in bytes.
+fn leading_number(s: &str) -> (Option<usize>, usize) {
+    let len = s.bytes().take_while(u8::is_ascii_digit).count();
+    (s[..len].parse().ok(), len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, path::PathBuf, process};
+
+    fn format(name: &str, frame: isize) -> String {
+        FramePattern::new(name).unwrap().format(frame)
+    }
+
+    #[test]
+    fn names_without_placeholders() {
+        assert_eq!(None, FramePattern::new("foo.nsi"));
+        assert_eq!(None, FramePattern::new("100%%.nsi"));
+        assert_eq!(None, FramePattern::new("$FOO/foo.nsi"));
+        assert_eq!(None, FramePattern::new("$F_END.nsi"));
+    }
+
+    #[test]
+    fn formats_placeholders() {
+        assert_eq!("foo.7.nsi", format("foo.@.nsi", 7));
+        assert_eq!("foo.0007.nsi", format("foo.@4.nsi", 7));
+        assert_eq!("foo.7.nsi", format("foo.#.nsi", 7));
+        assert_eq!("foo.0007.nsi", format("foo.####.nsi", 7));
+        assert_eq!("foo.7.nsi", format("foo.%d.nsi", 7));
+        assert_eq!("foo.0007.nsi", format("foo.%04d.nsi", 7));
+        assert_eq!("foo.   7.nsi", format("foo.%4d.nsi", 7));
+        assert_eq!("foo.7.nsi", format("foo.$F.nsi", 7));
+        assert_eq!("foo.0007.nsi", format("foo.$F4.nsi", 7));
+        assert_eq!("foo.0007.nsi", format("foo.${F4}.nsi", 7));
+        assert_eq!("foo.12345.nsi", format("foo.####.nsi", 12345));
+        assert_eq!("foo.-007.nsi", format("foo.####.nsi", -7));
+    }
+
+    #[test]
+    fn formats_all_placeholders_with_the_same_frame() {
+        assert_eq!("shot_0003/foo.3.nsi", format("shot_$F4/foo.@.nsi", 3));
+        assert_eq!("100%_0042.nsi", format("100%%_%04d.nsi", 42));
+    }
+
+    /// A folder with files for `frames` named after `pattern`.
+    struct Sequence(PathBuf);
+
+    impl Sequence {
+        fn new(test: &str, pattern: &str, frames: &[isize]) -> Self {
+            let root = env::temp_dir().join(format!("rdl-{test}-{}", process::id()));
+            let pattern = FramePattern::new(&format!("{}/{pattern}", root.display())).unwrap();
+
+            for &frame in frames {
+                let path = PathBuf::from(pattern.format(frame));
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, "").unwrap();
+            }
+
+            Self(root)
+        }
+
+        fn discover(&self, pattern: &str) -> Vec<isize> {
+            FramePattern::new(&format!("{}/{pattern}", self.0.display()))
+                .unwrap()
+                .discover()
+        }
+    }
+
+    impl Drop for Sequence {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn discovers_frames_with_gaps() {
+        let sequence = Sequence::new("discover", "foo.####.nsi", &[1, 2, 3, 5, 10]);
+        // Not padded like the pattern.
+        fs::write(sequence.0.join("foo.04.nsi"), "").unwrap();
+
+        let found = sequence.discover("foo.####.nsi");
+        assert_eq!(vec![1, 2, 3, 5, 10], found);
+        assert_eq!(vec![4, 6, 7, 8, 9], gaps(&found));
+        assert_eq!("4, 6-9", format_ranges(&gaps(&found)));
+
+        assert_eq!(vec![1, 2, 3, 5, 10], sequence.discover("foo.%04d.nsi"));
+        assert_eq!(vec![1, 2, 3, 5, 10], sequence.discover("foo.$F4.nsi"));
+        assert_eq!(Vec::<isize>::new(), sequence.discover("foo.@.nsi"));
+    }
+
+    #[test]
+    fn discovers_frames_in_folders() {
+        let sequence = Sequence::new("folders", "shot_@3/foo.@.nsi", &[8, 9, 11]);
+        // The frame of the folder and the file differ.
+        fs::create_dir_all(sequence.0.join("shot_012")).unwrap();
+        fs::write(sequence.0.join("shot_012/foo.13.nsi"), "").unwrap();
+
+        assert_eq!(vec![8, 9, 11], sequence.discover("shot_@3/foo.@.nsi"));
+    }
+
+    #[test]
+    fn formats_ranges() {
+        assert_eq!("", format_ranges(&[]));
+        assert_eq!("1-3, 7, 9-12", format_ranges(&[1, 2, 3, 7, 9, 10, 11, 12]));
+        assert_eq!("-2-0", format_ranges(&[-2, -1, 0]));
+    }
+}
@@ -6,7 +6,13 @@ use clap_complete::{
 };
 use fern::colors::{Color, ColoredLevelConfig};
 use human_panic::setup_panic;
-use std::io;
+use log::warn;
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    process::ExitCode,
+};
 
 mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
@@ -23,13 +29,58 @@ use rdl_cli::*;
 mod watch;
 use watch::*;
 
+mod config;
+use config::*;
+
+mod remap;
+use remap::*;
+
 mod render;
 use render::*;
 
-fn main() -> Result<()> {
+mod report;
+use report::*;
+
+mod deps;
+use deps::*;
+
+mod diff;
+use diff::*;
+
+mod input;
+use input::*;
+
+mod lint;
+use lint::*;
+
+mod pack;
+use pack::*;
+
+mod placeholder;
+use placeholder::*;
+
+mod stats;
+use stats::*;
+
+mod stream;
+
+fn main() -> ExitCode {
     setup_panic!();
 
-    run()
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {error:?}");
+
+            ExitCode::from(
+                error
+                    .downcast_ref::<RenderError>()
+                    .map(RenderError::exit_code)
+                    .or_else(|| error.downcast_ref::<DiffError>().map(DiffError::exit_code))
+                    .unwrap_or(1),
+            )
+        }
+    }
 }
 
 #[cfg(target_os = "windows")]
@@ -41,7 +92,7 @@ fn _sanitize_path_to_unc(path: &str) -> String {
 }
 
 fn run() -> Result<()> {
-    let cli = build_cli();
+    let (cli, matches) = build_cli();
 
     // Setup logging ==========================================================
 
@@ -60,7 +111,9 @@ fn run() -> Result<()> {
     let colors = ColoredLevelConfig::new().debug(Color::Magenta);
 
     fern::Dispatch::new()
-        .chain(std::io::stdout())
+        // Keep stdout clean for streams and reports written there, e.g. by
+        // `rdl cat`.
+        .chain(std::io::stderr())
         .format(move |out, message, record| {
             out.finish(format_args!(
                 "{}",
@@ -80,9 +133,15 @@ fn run() -> Result<()> {
     rlimit::setmaxstdio(8192).unwrap_or_else(|| warning!("Could not set maximum of open files"));
 
     // Execute subcommand =====================================================
-    match cli.command {
+    match apply_config(cli.command, &matches)? {
         Command::Render(args) => render(args),
         Command::Cat(args) => cat(args),
+        Command::Stats(args) => stats(args),
+        Command::Deps(args) => deps(args),
+        Command::Pack(args) => pack(args),
+        Command::Unpack(args) => unpack(args),
+        Command::Lint(args) => lint(args),
+        Command::Diff(args) => diff(args),
         Command::Watch(args) => watch(args),
         Command::Version => version(),
         Command::GenerateCompletions { shell } => generate_completions(shell),
@@ -148,43 +207,139 @@ fn version() -> Result<()> {
 }
 
 fn cat(args: Cat) -> Result<()> {
-    if let Some(file_name) = &args.file {
-        let path = args.output.clone().unwrap_or_else(|| "stdout".to_string());
+    let names = or_stdin(&args.file);
+    let stdin = read_stdin_if_named(&names)?;
+    let files = expand_globs(&names)?
+        .into_iter()
+        .map(|file_name| match (file_name.as_str(), &stdin) {
+            (STDIN, Some(stdin)) => stdin.path().to_string(),
+            _ => file_name,
+        })
+        .collect::<Vec<_>>();
 
-        let mut ctx_args = vec![nsi::string!("streamfilename", path.as_str())];
+    let remap = Remap::new(&args.input.remap, args.input.remap_file.as_deref())?;
+
+    // 3Delight only writes gzip. For zstd it writes an uncompressed copy that
+    // is compressed afterwards.
+    let zstd_copy = args.zstd.then(|| stream::temp_path("zstd"));
+
+    let path = match &zstd_copy {
+        Some(zstd_copy) => zstd_copy.to_string_lossy().into_owned(),
+        None => args.output.clone().unwrap_or_else(|| "stdout".to_string()),
+    };
+
+    let mut ctx_args = vec![nsi::string!("streamfilename", path.as_str())];
+
+    if args.binary {
+        ctx_args.push(nsi::string!("streamformat", "binarynsi"));
+    }
+
+    if args.gzip && !args.zstd {
+        ctx_args.push(nsi::string!("streamcompression", "gzip"));
+    }
 
-        if args.binary {
-            ctx_args.push(nsi::string!("streamformat", "binarynsi"));
+    let mut expand = vec!["apistream"];
+
+    if args.expand {
+        expand.push("dynamiclibrary");
+        expand.push("RiProcDynamicLoad");
+    }
+
+    ctx_args.push(nsi::strings!("executeprocedurals", &expand));
+
+    // Keep remapped copies alive until the context is dropped.
+    let mut remapped_streams = Vec::new();
+
+    // The stream is flushed when the context is dropped.
+    {
+        let Some(ctx) = nsi::Context::new(Some(&ctx_args)) else {
+            warn!("Could not create NSI context; falling back to built-in NSI parser");
+            return cat_native(&files, &args, remap.as_ref());
+        };
+
+        for file_name in &files {
+            let format = InputFormat::of(file_name, args.input.input_format.as_deref());
+            let remapped = match &remap {
+                Some(remap) => Some(remap.stream(file_name, format)?),
+                None => RemappedStream::decompressed(file_name)?,
+            };
+
+            ctx.evaluate(&[
+                nsi::string!("type", format.evaluate_type()),
+                nsi::integer!("nostream", true as _),
+                nsi::string!(
+                    "filename",
+                    remapped
+                        .as_ref()
+                        .map_or(file_name.as_str(), |remapped| remapped.path())
+                ),
+            ]);
+
+            remapped_streams.extend(remapped);
         }
+    }
+
+    if let Some(zstd_copy) = zstd_copy {
+        let bytes = fs::read(&zstd_copy);
+        fs::remove_file(&zstd_copy)?;
+        write_output(
+            args.output.as_deref(),
+            &stream::Compression::Zstd.compress(&bytes?)?,
+        )?;
+    }
+
+    Ok(())
+}
 
-        if args.gzip {
-            ctx_args.push(nsi::string!("streamcompression", "gzip"));
+/// Writes `bytes` to the file `output` or, if that is `None`, stdout.
+fn write_output(output: Option<&str>, bytes: &[u8]) -> Result<()> {
+    match output {
+        Some(output) => {
+            fs::write(output, bytes).map_err(|e| anyhow!("Could not write '{output}': {e}"))?
+        }
+        None => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(bytes)?;
+            stdout.flush()?;
         }
+    }
 
-        let mut expand = vec!["apistream"];
+    Ok(())
+}
 
-        if args.expand {
-            expand.push("dynamiclibrary");
-            expand.push("RiProcDynamicLoad");
+/// Re-emits NSI streams without going through `lib3delight`.
+fn cat_native(files: &[String], args: &Cat, remap: Option<&Remap>) -> Result<()> {
+    if args.expand {
+        return Err(anyhow!("--expand requires lib3delight to be installed"));
+    }
+
+    let mut statements = Vec::new();
+    for file_name in files {
+        if InputFormat::Lua == InputFormat::of(file_name, args.input.input_format.as_deref()) {
+            return Err(anyhow!("Lua NSI files require lib3delight to be installed"));
         }
 
-        ctx_args.push(nsi::strings!("executeprocedurals", &expand));
+        statements.extend(stream::read_file(Path::new(file_name))?);
+    }
+    if let Some(remap) = remap {
+        remap.apply_to_statements(&mut statements);
+    }
+    let commands = statements.iter().map(|statement| &statement.command);
 
-        let ctx = nsi::Context::new(Some(&ctx_args)).unwrap();
+    let mut contents = Vec::new();
+    if args.binary {
+        stream::write_binary(&mut contents, commands)?;
+    } else {
+        stream::write_ascii(&mut contents, commands)?;
+    }
 
-        ctx.evaluate(&[
-            nsi::string!(
-                "type",
-                if file_name.len() > 3 && ".lua" == &file_name[file_name.len() - 4..] {
-                    "lua"
-                } else {
-                    "apistream"
-                }
-            ),
-            nsi::integer!("nostream", true as _),
-            nsi::string!("filename", file_name.as_str()),
-        ]);
+    if args.zstd {
+        contents = stream::Compression::Zstd.compress(&contents)?;
+    } else if args.gzip {
+        contents = stream::Compression::Gzip.compress(&contents)?;
     }
 
+    write_output(args.output.as_deref(), &contents)?;
+
     Ok(())
 }
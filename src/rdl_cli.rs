@@ -1,8 +1,15 @@
-use clap::{Parser, Subcommand};
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
 
+/// Parses the command line.
+///
+/// The raw matches are returned alongside so callers can tell which
+/// arguments were given explicitly.
 #[inline]
-pub fn build_cli() -> Cli {
-    Cli::parse()
+pub fn build_cli() -> (Cli, ArgMatches) {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    (cli, matches)
 }
 
 #[derive(Parser)]
@@ -10,13 +17,17 @@ pub fn build_cli() -> Cli {
     name = "rdl",
     bin_name = "rdl",
     about = "Renders or filters NSI streams or Lua NSI files with 3Delight",
-    after_long_help = "",
+    after_long_help = "Every option can also be set through the RDL_* environment \
+        variable listed next to it. Flags take ‘true’ or ‘false’, counted \
+        flags like --verbose a number.\n\
+        Precedence: command line ➞ environment ➞ config file(s).",
     max_term_width = 120
 )]
 pub struct Cli {
     #[arg(
         display_order = 10,
         long,
+        env = "RDL_VERBOSE",
         short,
         action = clap::ArgAction::Count,
         help = "Verbosity level (-v verbose, -vv very verbose, etc.)",
@@ -30,6 +41,12 @@ pub struct Cli {
 pub enum Command {
     Render(Render),
     Cat(Cat),
+    Stats(Stats),
+    Deps(Deps),
+    Pack(Pack),
+    Unpack(Unpack),
+    Lint(Lint),
+    Diff(Diff),
     Watch(Watch),
     #[command(
         name = "version",
@@ -63,7 +80,6 @@ pub enum Command {
 
 #[derive(Parser, Clone)]
 #[command(
-    arg_required_else_help = true,
     about = "Render NSI file(s) with 3Delight",
 //    help_message = "Print this/a long help message."
 )]
@@ -71,19 +87,32 @@ pub struct Render {
     #[arg(
         name = "FILE",
         index = 1,
-        help = "The NSI FILE(s) to render",
+        help = "The NSI FILE(s) to render; ‘-’ or none for stdin",
         long_help = "The NSI FILE(s) to render\n\
-            Frame number placeholders are specified using @[padding]:\n\
-            foo.@.nsi   ➞  foo.1.nsi, foo.2.nsi, …\n\
-            foo.@4.nsi  ➞  foo.0001.nsi, foo.0002.nsi, …",
+            Use ‘-’, or give no FILE, to read the stream from stdin:\n\
+            generate_scene | rdl render\n\
+            Frame number placeholders are specified using @[padding], \
+            #…#, %[0[padding]]d or $F[padding]:\n\
+            foo.@.nsi    ➞  foo.1.nsi, foo.2.nsi, …\n\
+            foo.@4.nsi   ➞  foo.0001.nsi, foo.0002.nsi, …\n\
+            foo.####.nsi ➞  foo.0001.nsi, foo.0002.nsi, …\n\
+            foo.%04d.nsi ➞  foo.0001.nsi, foo.0002.nsi, …\n\
+            foo.$F4.nsi  ➞  foo.0001.nsi, foo.0002.nsi, …\n\
+            All placeholders in a FILE are replaced with the same frame. \
+            FILE(s) that exist are rendered as is, e.g. take#2/scene.nsi.\n\
+            The frames are found on disk; missing ones are reported. Use \
+            --frames to only render some of them.\n\
+            Glob patterns are expanded and their matches sorted naturally; \
+            hidden files are not matched:\n\
+            shots/**/*.nsi ➞  all .nsi files below shots/",
         value_hint = clap::ValueHint::FilePath
     )]
     pub file: Vec<String>,
 
     #[arg(
         long,
+        env = "RDL_COLLECTIVE",
         short = 'C',
-        conflicts_with = "cloud",
         help = "Use the given COLLECTIVE",
         long_help = "Render using the given 3Delight COLLECTIVE"
     )]
@@ -91,8 +120,8 @@ pub struct Render {
 
     #[arg(
         long,
+        env = "RDL_CLOUD",
         short,
-        conflicts_with = "collective",
         help = "Use 3Delight Cloud",
         long_help = "Render using 3Delight Cloud"
     )]
@@ -108,11 +137,17 @@ pub struct Render {
     )]
     pub display: bool,
     */
-    #[arg(long, short, help = "Print rendering progress at each bucket")]
+    #[arg(
+        long,
+        short,
+        env = "RDL_PROGRESS",
+        help = "Print rendering progress at each bucket"
+    )]
     pub progress: bool,
 
     #[arg(
         long,
+        env = "RDL_STATISTICS",
         short,
         action = clap::ArgAction::Count,
         help = "Generate statistics: (-s, -ss)",
@@ -124,6 +159,7 @@ pub struct Render {
 
     #[arg(
         long,
+        env = "RDL_THREADS",
         short,
         help = "Launch the render using number of THREADS",
         long_help = "Launch the render using number of THREADS\n\
@@ -132,6 +168,102 @@ pub struct Render {
     )]
     pub threads: Option<usize>,
 
+    #[arg(
+        long,
+        short,
+        env = "RDL_JOBS",
+        help = "Render up to JOBS frames concurrently",
+        long_help = "Render up to JOBS frames concurrently\n\
+            Each frame gets its own NSI context. THREADS – or, if no \
+            COLLECTIVE is used, the number of cores on the machine – are \
+            split evenly among the jobs."
+    )]
+    pub jobs: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        env = "RDL_MAX_INFLIGHT",
+        default_value_t = 16,
+        help = "Submit at most N frames to 3Delight Cloud at once",
+        long_help = "Submit at most N frames to 3Delight Cloud at once\n\
+            Frames are submitted in the order given by FRAMES. After a failed \
+            submission further ones are delayed, from one second doubling up \
+            to a minute while errors persist."
+    )]
+    pub max_inflight: usize,
+
+    #[arg(
+        long,
+        value_name = "N",
+        env = "RDL_RETRIES",
+        default_value_t = 0,
+        help = "Retry failed frames up to N times"
+    )]
+    pub retries: usize,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "RDL_RETRY_DELAY",
+        help = "Wait SECONDS before retrying a failed frame",
+        long_help = "Wait SECONDS before retrying a failed frame\n\
+            The delay doubles with each further attempt of the same frame:\n\
+            --retries 3 --retry-delay 10  ➞  retry after 10s, 20s, 40s"
+    )]
+    pub retry_delay: Option<f64>,
+
+    #[arg(
+        long,
+        env = "RDL_FAIL_FAST",
+        help = "Stop at the first frame that fails",
+        long_help = "Stop at the first frame that fails\n\
+            Frames already being rendered are finished; no new ones are \
+            started. A frame only counts as failed once all its retries are \
+            used up."
+    )]
+    pub fail_fast: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "RDL_REPORT",
+        help = "Write a JSON report of the render to FILE",
+        long_help = "Write a JSON report of the render to FILE\n\
+            For each frame it lists the input file, frame number, status, \
+            start & end time, wall time, renderer messages, output images \
+            written and – for renders on this machine, one at a time – the \
+            peak memory use. The peak memory use of the whole run is listed \
+            as well. Peak memory is only measured on Linux; elsewhere it is \
+            null. Input read from stdin is listed as ‘-’.",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub report: Option<String>,
+
+    #[arg(
+        long,
+        env = "RDL_SKIP_EXISTING",
+        help = "Skip frames whose output images are up to date",
+        long_help = "Skip frames whose output images are up to date\n\
+            The images are the 'imagefilename's of the stream's \
+            'outputdriver' nodes. A frame is skipped if all of them exist \
+            and are newer than the stream. Lua files are always rendered."
+    )]
+    pub skip_existing: bool,
+
+    #[arg(
+        long,
+        value_name = "REPORT",
+        env = "RDL_RESUME",
+        help = "Continue the run recorded in REPORT",
+        long_help = "Continue the run recorded in REPORT\n\
+            Frames that succeeded or were up to date in the REPORT written \
+            by --report are skipped. Without FILE(s) all frames of the \
+            REPORT are considered.",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub resume: Option<String>,
+
     /*
     #[arg(
         long,
@@ -141,6 +273,7 @@ pub struct Render {
     */
     #[arg(
         long,
+        env = "RDL_DRY_RUN",
         help = "Do not render, just print what would be done",
         long_help = "Do not render, just print the name of the file(s) to be \
             rendered"
@@ -149,6 +282,7 @@ pub struct Render {
 
     #[arg(
         long,
+        env = "RDL_FORCE_RENDER",
         help = "Add a render command to the NSI stream",
         long_help = "Add a render command to the NSI stream\n\
             Useful when the stream is missing this command.\n\
@@ -160,9 +294,12 @@ pub struct Render {
 
     #[arg(
         long,
+        env = "RDL_FRAMES",
         short,
         help = "FRAME(s) to render – 1,2,10-20,40-30@2",
         long_help = "FRAME(S) to render\n\
+            If omitted, all frames of FILE(s) with placeholders found on \
+            disk are rendered. Frames that do not exist are skipped.\n\
             They can be specified individually:\n\
             1,2,3,5,8,13\n\
             Or as a squence:\n\
@@ -180,22 +317,54 @@ pub struct Render {
             80-70@4  ➞  80, 76, 72"
     )]
     pub frames: Option<String>,
+
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    #[command(flatten)]
+    pub config: ConfigArgs,
 }
 
 #[derive(Parser)]
 #[command(
-    arg_required_else_help = true,
     about = "Dump the input as an NSI stream to stdout or a file",
 //    help_message = "Print this help message."
 )]
 pub struct Cat {
-    #[arg(long, short, help = "Encode NSI stream in binary format")]
+    #[arg(
+        long,
+        short,
+        env = "RDL_BINARY",
+        help = "Encode NSI stream in binary format",
+        long_help = "Encode NSI stream in binary format\n\
+            Without lib3delight, rdl writes its own binary encoding. rdl reads \
+            it back but 3Delight does not; convert it to ASCII with ‘rdl cat’ \
+            before rendering."
+    )]
     pub binary: bool,
 
-    #[arg(long, short, help = "Compress NSI stream using GNU zip format")]
+    #[arg(
+        long,
+        short,
+        env = "RDL_GZIP",
+        help = "Compress NSI stream using GNU zip format"
+    )]
     pub gzip: bool,
 
-    #[arg(long, short, help = "Expand archives and procedurals")]
+    #[arg(
+        long,
+        short,
+        env = "RDL_ZSTD",
+        help = "Compress NSI stream using Zstandard format"
+    )]
+    pub zstd: bool,
+
+    #[arg(
+        long,
+        short,
+        env = "RDL_EXPAND",
+        help = "Expand archives and procedurals"
+    )]
     pub expand: bool,
 
     //#[arg(long = "expand-archives", short = 'a', help = "Expand archives")]
@@ -205,18 +374,210 @@ pub struct Cat {
     //pub expand_procedurals: bool,
     #[arg(
         name = "FILE",
-        help = "The NSI FILE to dump",
+        help = "The NSI FILE(s) to dump; ‘-’ or none for stdin",
+        long_help = "The NSI FILE(s) to dump\n\
+            Use ‘-’, or give no FILE, to read the stream from stdin.\n\
+            Multiple FILEs are dumped as one stream, in order. Gzip and \
+            zstd compressed FILEs are decompressed. Glob patterns \
+            are expanded and their matches sorted naturally:\n\
+            shots/**/*.nsi  ➞  all .nsi files below shots/",
         value_hint = clap::ValueHint::FilePath
     )]
-    pub file: Option<String>,
+    pub file: Vec<String>,
 
     #[arg(
         long,
+        env = "RDL_CAT_OUTPUT",
         short,
         help = "Dump NSI stream to OUTPUT",
         long_help = "Dump NSI stream to OUTPUT instead of stdout"
     )]
     pub output: Option<String>,
+
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[derive(Parser)]
+#[command(
+    arg_required_else_help = true,
+    about = "Print a summary of the contents of an NSI stream",
+    after_long_help = "Lights are counted as environment nodes plus shader nodes \
+        whose shader file name ends in ‘light’, e.g. pointLight.oso, ignoring \
+        case."
+)]
+pub struct Stats {
+    #[arg(
+        name = "FILE",
+        help = "The NSI FILE to summarize",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub file: String,
+
+    #[arg(
+        long,
+        env = "RDL_STATS_JSON",
+        short,
+        help = "Print the summary as JSON",
+        long_help = "Print the summary as JSON instead of a table"
+    )]
+    pub json: bool,
+}
+
+#[derive(Parser)]
+#[command(
+    arg_required_else_help = true,
+    about = "List the files NSI stream(s) depend on"
+)]
+pub struct Deps {
+    #[arg(
+        name = "FILE",
+        index = 1,
+        help = "The NSI FILE(s) to list dependencies of",
+        long_help = "The NSI FILE(s) to list dependencies of\n\
+            Shaders, textures, procedurals, Lua files and archives are \
+            listed. Archives that are NSI streams are followed \
+            recursively.",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub file: Vec<String>,
+
+    #[arg(
+        long,
+        short,
+        env = "RDL_MISSING",
+        help = "Only list dependencies that are missing"
+    )]
+    pub missing: bool,
+}
+
+#[derive(Parser)]
+#[command(
+    arg_required_else_help = true,
+    about = "Bundle an NSI stream and all its dependencies into an archive"
+)]
+pub struct Pack {
+    #[arg(
+        name = "FILE",
+        help = "The NSI FILE to pack",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub file: String,
+
+    #[arg(
+        long,
+        env = "RDL_PACK_OUTPUT",
+        short,
+        help = "Write the bundle to OUTPUT",
+        long_help = "Write the bundle to OUTPUT\n\
+            The bundle is a GNU zip compressed tar archive unless OUTPUT ends \
+            in ‘.tar’.\n\
+            Files inside the FILE's folder keep their relative location. All \
+            other files are stored under ‘external/’. Paths in the stream(s) \
+            are rewritten to be relative to the root of the bundle.",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub output: String,
+}
+
+#[derive(Parser)]
+#[command(
+    arg_required_else_help = true,
+    about = "Extract a bundle created with ‘rdl pack’ and verify its checksums"
+)]
+pub struct Unpack {
+    #[arg(
+        name = "FILE",
+        help = "The bundle to unpack",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub file: String,
+
+    #[arg(
+        long,
+        env = "RDL_UNPACK_OUTPUT",
+        short,
+        help = "Extract into the OUTPUT folder",
+        long_help = "Extract into the OUTPUT folder\n\
+            If not specified the bundle's file name without .tar, .tgz or .gz \
+            is used.",
+        value_hint = clap::ValueHint::DirPath
+    )]
+    pub output: Option<String>,
+}
+
+#[derive(Parser)]
+#[command(
+    arg_required_else_help = true,
+    about = "Check NSI file(s) for errors without rendering them"
+)]
+pub struct Lint {
+    #[arg(
+        name = "FILE",
+        index = 1,
+        help = "The NSI FILE(s) to check",
+        long_help = "The NSI FILE(s) to check\n\
+            NSI archives a FILE evaluates are checked as part of it; their \
+            problems are reported at the Evaluate.",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub file: Vec<String>,
+
+    #[arg(
+        long,
+        env = "RDL_ARCHIVE",
+        short,
+        help = "Treat the FILE(s) as archives",
+        long_help = "Treat the FILE(s) as archives\n\
+            Skips checks that only make sense for a complete scene, e.g. \
+            whether nodes are connected to .root or whether there is an \
+            outputdriver ➞ outputlayer ➞ screen ➞ camera chain."
+    )]
+    pub archive: bool,
+}
+
+#[derive(Parser)]
+#[command(
+    arg_required_else_help = true,
+    about = "Compare two NSI streams semantically",
+    after_long_help = "The exit code is 0 if the streams are the same, 1 if they differ and 2 if they could not be compared, like diff(1)."
+)]
+pub struct Diff {
+    #[arg(
+        name = "OLD",
+        help = "The NSI file to compare against",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub old: String,
+
+    #[arg(
+        name = "NEW",
+        help = "The NSI file to compare",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub new: String,
+
+    #[arg(
+        long,
+        env = "RDL_TOLERANCE",
+        short,
+        default_value = "0.000001",
+        help = "Maximum difference between floating point values considered equal"
+    )]
+    pub tolerance: f64,
+
+    #[arg(
+        long,
+        env = "RDL_DIFF_JSON",
+        short,
+        help = "Print the differences as JSON",
+        long_help = "Print the differences as JSON\n\
+            Attribute values are given in ASCII NSI notation."
+    )]
+    pub json: bool,
 }
 
 #[derive(Parser)]
@@ -236,25 +597,143 @@ pub struct Watch {
 
     #[arg(
         long,
+        env = "RDL_COLLECTIVE",
         short = 'C',
-        conflicts_with = "cloud",
         help = "Render using the the given 3Delight COLLECTIVE"
     )]
     pub collective: Option<String>,
 
-    #[arg(
-        long,
-        short,
-        conflicts_with = "collective",
-        help = "Render using 3Delight Cloud"
-    )]
+    #[arg(long, env = "RDL_CLOUD", short, help = "Render using 3Delight Cloud")]
     pub cloud: bool,
 
     #[arg(
         long,
+        env = "RDL_RECURSIVE",
         short,
         help = "Recurse into the given folder(s)",
         long_help = "Recurse into the given folder(s) when looking for new files to render"
     )]
     pub recursive: bool,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "RDL_SETTLE",
+        default_value_t = 2.0,
+        help = "Render files once unchanged for SECONDS",
+        long_help = "Render files once unchanged for SECONDS\n\
+            New, modified and moved-in files are rendered once their size and \
+            modification time have not changed for SECONDS, so files still \
+            being written are not rendered half-complete. Temporary files, \
+            e.g. .foo.nsi, foo.nsi~ or foo.nsi.part, are ignored."
+    )]
+    pub settle: f64,
+
+    #[arg(
+        long,
+        value_name = "SUFFIX",
+        env = "RDL_SENTINEL",
+        help = "Render files once FILE+SUFFIX exists",
+        long_help = "Render files once FILE+SUFFIX exists\n\
+            Instead of waiting for files to settle, wait for the exporter to \
+            create a sentinel file next to them:\n\
+            --sentinel .done  ➞  foo.nsi is rendered once foo.nsi.done exists"
+    )]
+    pub sentinel: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        env = "RDL_INCLUDE",
+        default_values = ["*.nsi", "*.nsi.gz", "*.lua"],
+        help = "Only render files matching PATTERN",
+        long_help = "Only render files matching PATTERN\n\
+            Can be given multiple times. Patterns are matched ignoring case \
+            against the file name or, if they contain a ‘/’, against the path \
+            below the watched folder:\n\
+            --include '*.nsi.zst'       ➞  foo.nsi.zst\n\
+            --include 'shots/*/*.nsi'   ➞  shots/010/foo.nsi"
+    )]
+    pub include: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        env = "RDL_EXCLUDE",
+        help = "Do not render files matching PATTERN",
+        long_help = "Do not render files matching PATTERN\n\
+            Matched like --include. Can be given multiple times.\n\
+            Patterns are also read from a .rdlignore file in each watched \
+            folder and its subfolders, one per line, relative to the folder \
+            of the .rdlignore. Patterns without a ‘/’ match file and folder \
+            names at any depth, a trailing ‘/’ only matches folders and a \
+            leading ‘!’ re-includes what an earlier .rdlignore pattern \
+            matched; the last matching pattern wins. Empty lines and lines starting with \
+            ‘#’ are ignored."
+    )]
+    pub exclude: Vec<String>,
+
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+/// Options for reading the input files of `render`, `cat` and `watch`.
+#[derive(Args, Clone)]
+pub struct InputArgs {
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        env = "RDL_INPUT_FORMAT",
+        value_parser = clap::builder::PossibleValuesParser::new(["nsi", "binary", "lua"]),
+        help = "Read input files as FORMAT instead of detecting it",
+        long_help = "Read input files as FORMAT instead of detecting it\n\
+            By default files ending in .lua – ignoring case and a .gz, .zst \
+            or .zstd suffix – are read as Lua and all others as NSI. Use ‘lua’ to read \
+            Lua from stdin."
+    )]
+    pub input_format: Option<String>,
+
+    #[arg(
+        long,
+        env = "RDL_REMAP",
+        value_name = "FROM=TO",
+        help = "Replace path prefix FROM with TO",
+        long_help = "Replace path prefix FROM with TO\n\
+            Applies to all string attributes in the stream, e.g. texture, \
+            shader and archive paths. Can be given multiple times; the \
+            longest matching FROM wins.\n\
+            /mnt/projects=/data  ➞  /mnt/projects/a.tdl becomes /data/a.tdl"
+    )]
+    pub remap: Vec<String>,
+
+    #[arg(
+        long,
+        env = "RDL_REMAP_FILE",
+        value_name = "TABLE",
+        help = "Read path remapping rules from TABLE",
+        long_help = "Read path remapping rules from TABLE\n\
+            One FROM=TO rule per line. Empty lines and lines starting with \
+            ‘#’ are ignored.",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    pub remap_file: Option<String>,
+}
+
+/// Options for reading the config files of `render`, `cat` and `watch`.
+#[derive(Args, Clone)]
+pub struct ConfigArgs {
+    #[arg(
+        long,
+        env = "RDL_PROFILE",
+        help = "Use the settings of PROFILE from the config file(s)",
+        long_help = "Use the settings of PROFILE from the config file(s)\n\
+            Settings are read from $XDG_CONFIG_HOME/rdl/config.toml and the \
+            first rdl.toml found in the current folder or its parents. The \
+            latter take precedence. [profile.PROFILE] tables are layered on \
+            top. Options given on the command line always win."
+    )]
+    pub profile: Option<String>,
 }
@@ -0,0 +1,402 @@
+use crate::{
+    references, resolve,
+    stream::{self, Statement, Value},
+    DependencyKind, InputFormat, Result,
+};
+use anyhow::anyhow;
+use log::{debug, warn};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Read},
+    path::{Path, PathBuf},
+};
+
+/// Path prefix substitution rules.
+#[derive(Clone, Debug, Default)]
+pub struct Remap {
+    /// `(from, to)`, longest `from` first.
+    rules: Vec<(String, String)>,
+}
+
+impl Remap {
+    /// Builds rules from `FROM=TO` strings and an optional table file.
+    ///
+    /// The table file contains one `FROM=TO` rule per line. Empty lines and
+    /// lines starting with `#` are ignored.
+    ///
+    /// Rules given on the command line win over table rules with the same
+    /// `FROM`.
+    ///
+    /// Returns `None` if there are no rules.
+    pub fn new(rules: &[String], table_file: Option<&str>) -> Result<Option<Self>> {
+        let mut remap = Remap::default();
+
+        for rule in rules {
+            remap.add_rule(rule)?;
+        }
+
+        if let Some(table_file) = table_file {
+            let table = fs::read_to_string(table_file)
+                .map_err(|e| anyhow!("Could not read remap table '{table_file}': {e}"))?;
+
+            for (number, line) in table.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                remap
+                    .add_rule(line)
+                    .map_err(|e| anyhow!("{table_file}:{}: {e}", number + 1))?;
+            }
+        }
+
+        if remap.rules.is_empty() {
+            return Ok(None);
+        }
+
+        // The sort is stable, so command line rules stay ahead of table
+        // rules with the same `from`.
+        remap
+            .rules
+            .sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+
+        Ok(Some(remap))
+    }
+
+    fn add_rule(&mut self, rule: &str) -> Result<()> {
+        match rule.split_once('=') {
+            Some((from, to)) if !from.trim().is_empty() => {
+                self.rules
+                    .push((from.trim().to_string(), to.trim().to_string()));
+                Ok(())
+            }
+            _ => Err(anyhow!("Invalid remap rule '{rule}', expected FROM=TO")),
+        }
+    }
+
+    /// Applies the first matching rule to `path`.
+    ///
+    /// A rule only matches at a path component boundary, i.e. `/mnt/projects`
+    /// matches `/mnt/projects/foo` but not `/mnt/projects2/foo`.
+    pub fn apply(&self, path: &str) -> Option<String> {
+        self.rules.iter().find_map(|(from, to)| {
+            let rest = path.strip_prefix(from.as_str())?;
+            if rest.is_empty() || rest.starts_with(['/', '\\']) || from.ends_with(['/', '\\']) {
+                Some(format!("{to}{rest}"))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Rewrites all matching string arguments.
+    pub fn apply_to_statements(&self, statements: &mut [Statement]) {
+        statements
+            .iter_mut()
+            .filter_map(|statement| statement.command.args_mut())
+            .flatten()
+            .for_each(|arg| {
+                if let Value::Strings(strings) = &mut arg.value {
+                    for s in strings.iter_mut() {
+                        if let Some(remapped) = self.apply(s) {
+                            debug!("Remapping '{s}' ➞ '{remapped}'");
+                            *s = remapped;
+                        }
+                    }
+                }
+            });
+    }
+
+    /// Writes a copy of the stream at `file_name` with all paths remapped.
+    ///
+    /// NSI archives the stream references are remapped recursively. The
+    /// copies are deleted when the returned [`RemappedStream`] is dropped.
+    pub fn stream(&self, file_name: &str, format: InputFormat) -> Result<RemappedStream> {
+        let mut remapped = RemappedStream::default();
+
+        if InputFormat::Lua == format {
+            warn!("Paths inside Lua file '{file_name}' are not remapped");
+            match RemappedStream::decompressed(file_name)? {
+                Some(decompressed) => remapped = decompressed,
+                None => remapped.path = PathBuf::from(file_name),
+            }
+        } else {
+            remapped.path = self.remap_file(
+                Path::new(file_name),
+                &mut HashMap::new(),
+                &mut remapped.temp_files,
+            )?;
+        }
+
+        Ok(remapped)
+    }
+
+    fn remap_file(
+        &self,
+        path: &Path,
+        visited: &mut HashMap<PathBuf, PathBuf>,
+        temp_files: &mut Vec<PathBuf>,
+    ) -> Result<PathBuf> {
+        let temp_path = stream::copy_path(path, "remap");
+        temp_files.push(temp_path.clone());
+        visited.insert(path.to_path_buf(), temp_path.clone());
+
+        let mut statements = stream::read_file(path)?;
+
+        self.apply_to_statements(&mut statements);
+
+        // Point references to NSI archives at their remapped copies.
+        let mut archives = HashMap::<String, String>::new();
+        for (kind, file_name) in references(&statements) {
+            if DependencyKind::Archive != kind {
+                continue;
+            }
+
+            let resolved = resolve(path, kind, &file_name);
+            let archive = match visited.get(&resolved) {
+                Some(archive) => archive.clone(),
+                None if resolved.exists() => self.remap_file(&resolved, visited, temp_files)?,
+                None => continue,
+            };
+            // Refer to a copy next to the archive the same way as to the
+            // archive itself, so relative references stay relative.
+            let archive = match archive.file_name() {
+                Some(name) if archive.parent() == resolved.parent() => {
+                    Path::new(&file_name).with_file_name(name)
+                }
+                _ => archive,
+            };
+            archives.insert(file_name, archive.to_string_lossy().to_string());
+        }
+
+        if !archives.is_empty() {
+            Remap {
+                rules: archives.into_iter().collect(),
+            }
+            .apply_exact(&mut statements);
+        }
+
+        // 3Delight reads the copy, so it is ASCII even if the original is
+        // binary.
+        let commands = statements.iter().map(|statement| &statement.command);
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        stream::write_ascii(&mut writer, commands)?;
+
+        Ok(temp_path)
+    }
+
+    /// Replaces strings that are identical to a rule's `from`.
+    fn apply_exact(&self, statements: &mut [Statement]) {
+        statements
+            .iter_mut()
+            .filter_map(|statement| statement.command.args_mut())
+            .flatten()
+            .for_each(|arg| {
+                if let Value::Strings(strings) = &mut arg.value {
+                    for s in strings.iter_mut() {
+                        if let Some((_, to)) = self.rules.iter().find(|(from, _)| from == s) {
+                            *s = to.clone();
+                        }
+                    }
+                }
+            });
+    }
+}
+
+/// A remapped or decompressed copy of an NSI stream.
+#[derive(Debug, Default)]
+pub struct RemappedStream {
+    path: PathBuf,
+    temp_files: Vec<PathBuf>,
+}
+
+impl RemappedStream {
+    /// Writes a decompressed copy of the stream at `file_name` if it is gzip
+    /// or zstd compressed.
+    ///
+    /// Like remapped copies, it is written next to the original.
+    ///
+    /// Returns `None` if the stream is not compressed.
+    pub fn decompressed(file_name: &str) -> Result<Option<Self>> {
+        let mut magic = Vec::with_capacity(4);
+        File::open(file_name)
+            .and_then(|file| file.take(4).read_to_end(&mut magic))
+            .map_err(|e| anyhow!("Could not read '{file_name}': {e}"))?;
+
+        let Some(compression) = stream::Compression::detect(&magic) else {
+            return Ok(None);
+        };
+
+        debug!("Decompressing {compression} stream '{file_name}'");
+
+        let temp_path = stream::copy_path(Path::new(file_name), "decompressed");
+        let decompressed = Self {
+            path: temp_path.clone(),
+            temp_files: vec![temp_path.clone()],
+        };
+        fs::write(&temp_path, stream::read_bytes(Path::new(file_name))?)?;
+
+        Ok(Some(decompressed))
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap_or_default()
+    }
+}
+
+impl Drop for RemappedStream {
+    fn drop(&mut self) {
+        for temp_file in &self.temp_files {
+            match fs::remove_file(temp_file) {
+                // The stream failed before the copy was written.
+                Err(error) if io::ErrorKind::NotFound == error.kind() => {}
+                Err(error) => warn!("Could not remove '{}': {error}", temp_file.display()),
+                Ok(()) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn remap(rules: &[&str]) -> Remap {
+        let rules = rules
+            .iter()
+            .map(|rule| rule.to_string())
+            .collect::<Vec<_>>();
+        Remap::new(&rules, None).unwrap().unwrap()
+    }
+
+    /// A folder that is removed when dropped.
+    struct Folder(PathBuf);
+
+    impl Folder {
+        fn new(test: &str) -> Self {
+            let path = env::temp_dir().join(format!("rdl-{test}-{}", process::id()));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for Folder {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn matches_prefixes() {
+        let remap = remap(&[
+            "/mnt/projects=/data",
+            "/mnt/projects/big = /big",
+            "C:\\=/c/",
+        ]);
+
+        assert_eq!(
+            Some("/data/a.tdl".into()),
+            remap.apply("/mnt/projects/a.tdl")
+        );
+        assert_eq!(Some("/data".into()), remap.apply("/mnt/projects"));
+        assert_eq!(
+            Some("/big/a.tdl".into()),
+            remap.apply("/mnt/projects/big/a.tdl")
+        );
+        assert_eq!(
+            Some("/data/bigger".into()),
+            remap.apply("/mnt/projects/bigger")
+        );
+        assert_eq!(None, remap.apply("/mnt/projects2/a.tdl"));
+        assert_eq!(None, remap.apply("mnt/projects/a.tdl"));
+        assert_eq!(Some("/c/a.tdl".into()), remap.apply("C:\\a.tdl"));
+    }
+
+    #[test]
+    fn reads_rules() {
+        let folder = Folder::new("remap-rules");
+        let table = folder.0.join("remap.txt");
+        fs::write(&table, "# Comment\n\n/a=/b\n  /c = /d  \n").unwrap();
+        let table = table.to_str();
+
+        let remap = Remap::new(&["/a=/e".into()], table).unwrap().unwrap();
+        assert_eq!(
+            vec![
+                ("/a".into(), "/e".into()),
+                ("/a".into(), "/b".into()),
+                ("/c".into(), "/d".into())
+            ],
+            remap.rules
+        );
+        assert_eq!(Some("/e/x.oso".into()), remap.apply("/a/x.oso"));
+        assert_eq!(Some("/d/x.oso".into()), remap.apply("/c/x.oso"));
+
+        assert!(Remap::new(&[], None).unwrap().is_none());
+        assert_eq!(
+            "Invalid remap rule '=/b', expected FROM=TO",
+            Remap::new(&["=/b".into()], None).unwrap_err().to_string()
+        );
+
+        fs::write(folder.0.join("remap.txt"), "/a=/b\n/c\n").unwrap();
+        assert_eq!(
+            format!(
+                "{}:2: Invalid remap rule '/c', expected FROM=TO",
+                table.unwrap()
+            ),
+            Remap::new(&[], table).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn remaps_archives_next_to_the_originals() {
+        let folder = Folder::new("remap-stream");
+        fs::create_dir(folder.0.join("sub")).unwrap();
+        fs::write(
+            folder.0.join("scene.nsi"),
+            "Evaluate \"filename\" \"string\" 1 \"sub/a.nsi\" \"type\" \"string\" 1 \"apistream\"\n",
+        )
+        .unwrap();
+        fs::write(
+            folder.0.join("sub/a.nsi"),
+            "Create \"s\" \"shader\"\nSetAttribute \"s\" \"shaderfilename\" \"string\" 1 \"/a/x.oso\"\n",
+        )
+        .unwrap();
+
+        let stream = remap(&["/a=/b"])
+            .stream(
+                folder.0.join("scene.nsi").to_str().unwrap(),
+                InputFormat::Nsi,
+            )
+            .unwrap();
+        let copies = stream.temp_files.clone();
+
+        let scene = fs::read_to_string(stream.path()).unwrap();
+        let archive = copies
+            .iter()
+            .find(|copy| copy.parent() == Some(&folder.0.join("sub")));
+        let archive_name = archive.unwrap().file_name().unwrap().to_str().unwrap();
+        assert!(scene.contains(&format!("\"sub/{archive_name}\"")));
+        assert!(fs::read_to_string(archive.unwrap())
+            .unwrap()
+            .contains("\"/b/x.oso\""));
+
+        drop(stream);
+        assert!(copies.iter().all(|copy| !copy.exists()));
+    }
+
+    #[test]
+    fn removes_copies_of_unreadable_streams() {
+        let folder = Folder::new("remap-unreadable");
+        fs::write(folder.0.join("scene.nsi"), "Create \"s\"\n").unwrap();
+
+        assert!(remap(&["/a=/b"])
+            .stream(
+                folder.0.join("scene.nsi").to_str().unwrap(),
+                InputFormat::Nsi,
+            )
+            .is_err());
+        assert_eq!(1, fs::read_dir(&folder.0).unwrap().count());
+    }
+}
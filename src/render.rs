@@ -1,8 +1,89 @@
-use crate::{Render, Result};
-use anyhow::{anyhow, Error};
+use crate::{
+    expand_globs, format_ranges, gaps, or_stdin, peak_memory, read_stdin_if_named,
+    reset_peak_memory, stream_output_images, FramePattern, InputFormat, Remap, RemappedStream,
+    Render, RenderReport, Result, STDIN,
+};
+use anyhow::anyhow;
 use frame_sequence::parse_frame_sequence;
 use log::{debug, error, info, trace, warn, Level};
-use std::thread;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error, fmt, fs, mem,
+    num::NonZeroUsize,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Delay after the first failed submission when rendering concurrently.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Upper limit for the delay after consecutive failed submissions.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Upper limit for the delay before retrying a failed file.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+struct Backoff {
+    delay: Duration,
+    not_before: Instant,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            delay: Duration::ZERO,
+            not_before: Instant::now(),
+        }
+    }
+}
+
+/// Errors of [`render()`] that map to a distinct process exit code.
+#[derive(Debug)]
+pub enum RenderError {
+    /// Some or all files failed to render.
+    FramesFailed { failed: usize, total: usize },
+    /// Input files that do not exist.
+    InputNotFound(Vec<String>),
+    /// The 3Delight library could not be loaded.
+    LibraryNotLoadable,
+    /// The `--frames` expression could not be parsed.
+    InvalidFrameExpression(String),
+}
+
+impl RenderError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            RenderError::FramesFailed { .. } => 3,
+            RenderError::InputNotFound(_) => 4,
+            RenderError::LibraryNotLoadable => 5,
+            RenderError::InvalidFrameExpression(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::FramesFailed { failed, total } => {
+                write!(f, "{failed} of {total} file(s) failed")
+            }
+            RenderError::InputNotFound(file_names) => {
+                write!(f, "Input file(s) not found: '{}'", file_names.join("', '"))
+            }
+            RenderError::LibraryNotLoadable => {
+                write!(f, "Could not load the 3Delight library")
+            }
+            RenderError::InvalidFrameExpression(error) => {
+                write!(f, "Error in frame sequence expression{error}")
+            }
+        }
+    }
+}
+
+impl error::Error for RenderError {}
 
 /*fn render(args: Render) -> Result<()> {
     let frame_sequence = if let Some(frame_sequence_string) = &args.frames {
@@ -81,104 +162,471 @@ use std::thread;
     Ok(())
 }*/
 
-pub fn render(args: Render) -> Result<()> {
+pub fn render(mut args: Render) -> Result<()> {
     let frame_sequence = if let Some(frame_sequence_string) = &args.frames {
         parse_frame_sequence(frame_sequence_string)
-            .map_err(|e| anyhow!("Error in frame sequence expression{e}"))?
+            .map_err(|e| RenderError::InvalidFrameExpression(e.to_string()))?
     } else {
         vec![]
     };
 
+    if !args.dry_run && nsi::Context::new(None).is_none() {
+        return Err(RenderError::LibraryNotLoadable.into());
+    }
+
     let cloud = args.cloud;
+    let jobs = if cloud {
+        args.max_inflight
+    } else {
+        args.jobs.unwrap_or(1)
+    }
+    .max(1);
+
+    // Split the threads among concurrent local jobs.
+    if !cloud && 1 < jobs {
+        if let Some(thread_count) = args.threads.or_else(|| {
+            args.collective
+                .is_none()
+                .then(|| thread::available_parallelism().map_or(1, NonZeroUsize::get))
+        }) {
+            args.threads = Some((thread_count / jobs).max(1));
+        }
+    }
 
-    let error_handler_arg = nsi::callback!(
-        "errorhandler",
-        nsi::ErrorCallback::new(|level: Level, error: i32, message: &str| match level {
-            Level::Error => error!("[{error}] {message}"),
-            Level::Warn => warn!("[{}] {}", error, message),
-            Level::Info => info!("[{}] {}", error, message),
-            Level::Debug => debug!("[{}] {}", error, message),
-            Level::Trace => trace!("[{}] {}", error, message),
-        })
-    );
+    let mut queue = VecDeque::new();
+    let mut missing = Vec::new();
+    let mut up_to_date = Vec::new();
+    // The output images of each stream, by file name.
+    let mut output_images = HashMap::new();
+
+    let remap = Remap::new(&args.input.remap, args.input.remap_file.as_deref())?;
+    let resumed = args.resume.as_deref().map(RenderReport::read).transpose()?;
 
-    args.file
+    // Without FILE(s) read stdin, unless continuing a previous run.
+    let names = if args.resume.is_some() {
+        args.file.clone()
+    } else {
+        or_stdin(&args.file)
+    };
+    // A continued run reads stdin again if that was not rendered yet.
+    let resumed_names = resumed
         .iter()
+        .filter(|_| args.file.is_empty())
+        .flat_map(|resumed| &resumed.frames)
+        .filter(|frame| !frame.status.is_done())
+        .map(|frame| frame.input.clone());
+    let stdin = read_stdin_if_named(
+        &names
+            .iter()
+            .cloned()
+            .chain(resumed_names)
+            .collect::<Vec<_>>(),
+    )?;
+    // Reports name stdin `-` rather than the temporary file.
+    let input_name = |file_name: &str| match &stdin {
+        Some(stdin) if stdin.path() == file_name => STDIN.to_string(),
+        _ => file_name.to_string(),
+    };
+
+    let mut files = expand_globs(&names)?
+        .into_iter()
         .flat_map(|file_name| {
-            if let Some(pos) = file_name.find('@') {
-                // FIXME: this will fail if padding is wider than one digit!
-                let padding = if let Some(number) = file_name.get(pos + 1..pos + 2) {
-                    number.parse::<usize>().unwrap_or(0)
-                } else {
-                    0
-                };
+            if let (STDIN, Some(stdin)) = (file_name.as_str(), &stdin) {
+                vec![(stdin.path().to_string(), None)]
+            } else if let Some(pattern) = FramePattern::new(&file_name)
+                // Like with globs, names of existing files are taken as is.
+                .filter(|_| !Path::new(&file_name).exists())
+            {
+                let found = pattern.discover();
+                if found.is_empty() {
+                    missing.push(file_name);
+                    return Vec::new();
+                }
 
-                let frame_number_placeholder = if padding != 0 {
-                    file_name.get(pos..pos + 2).unwrap()
+                let gaps = gaps(&found);
+                if !gaps.is_empty() {
+                    warn!("'{file_name}' is missing frame(s) {}", format_ranges(&gaps));
+                }
+
+                // Render the frames found, in the order given by `--frames`.
+                let frames = if frame_sequence.is_empty() {
+                    info!("Found frame(s) {} of '{file_name}'", format_ranges(&found));
+                    found
                 } else {
-                    "@"
+                    let (frames, mut not_found) = frame_sequence
+                        .iter()
+                        .partition::<Vec<_>, _>(|frame| found.binary_search(frame).is_ok());
+                    if !not_found.is_empty() {
+                        not_found.sort_unstable();
+                        not_found.dedup();
+                        warn!(
+                            "Skipping frame(s) {} of '{file_name}'; not found",
+                            format_ranges(&not_found)
+                        );
+                    }
+                    if frames.is_empty() {
+                        missing.push(file_name.clone());
+                    }
+                    frames
                 };
 
-                // Build frame sequence.
-                frame_sequence
-                    .iter()
-                    .map(|frame| {
-                        let frame_string = if padding != 0 {
-                            format!("{:0width$}", frame, width = padding)
-                        } else {
-                            format!("{frame}")
-                        };
-
-                        // We do not check if the file exists at all.
-                        // the `Context::evaluate()` call will fail on the
-                        // side of the renderer later, if it doesn't.
-                        file_name.replace(frame_number_placeholder, &frame_string)
-                    })
+                frames
+                    .into_iter()
+                    .map(|frame| (pattern.format(frame), Some(frame)))
                     .collect()
             } else {
-                vec![file_name.clone()]
+                vec![(file_name, None)]
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Continue the previous run as is if no files were given.
+    if let (true, Some(resumed)) = (args.file.is_empty(), &resumed) {
+        files = resumed
+            .frames
+            .iter()
+            .map(|frame| match (frame.input.as_str(), &stdin) {
+                (STDIN, Some(stdin)) => (stdin.path().to_string(), frame.frame),
+                _ => (frame.input.clone(), frame.frame),
+            })
+            .collect();
+    }
+
+    let done = resumed.map_or_else(HashSet::new, |resumed| {
+        resumed
+            .frames
+            .into_iter()
+            .filter(|frame| frame.status.is_done())
+            .map(|frame| (frame.input, frame.frame))
+            .collect::<HashSet<_>>()
+    });
+
+    files
+        .into_iter()
+        .filter(|(file_name, frame)| {
+            // Frames done in a previous run, e.g. from stdin, are not read.
+            let exists =
+                Path::new(file_name).exists() || done.contains(&(input_name(file_name), *frame));
+            if !exists {
+                missing.push(file_name.clone());
+            }
+            exists
+        })
+        .filter(|(file_name, frame)| {
+            if done.contains(&(input_name(file_name), *frame)) {
+                info!("Skipping '{file_name}': rendered in the previous run");
+                up_to_date.push((file_name.clone(), *frame));
+                return false;
+            }
+
+            // Each stream is parsed once, for both `--skip-existing` and
+            // the report.
+            if args.skip_existing || args.report.is_some() {
+                output_images.entry(file_name.clone()).or_insert_with(|| {
+                    read_output_images(
+                        file_name,
+                        args.input.input_format.as_deref(),
+                        remap.as_ref(),
+                    )
+                });
             }
+
+            let skip = args.skip_existing
+                && outputs_up_to_date(file_name, &output_images[file_name.as_str()]);
+            if skip {
+                info!("Skipping '{file_name}': output image(s) are up to date");
+                up_to_date.push((file_name.clone(), *frame));
+            }
+            !skip
         })
-        .filter_map(|file_name| {
-            let mut ctx_args = vec![error_handler_arg.clone()];
+        .for_each(|(file_name, frame)| {
+            let mut ctx_args = Vec::with_capacity(2);
 
             if cloud {
                 ctx_args.push(nsi::integer!("cloud", true as _));
-                let file_name = file_name.clone();
-                let args = args.clone();
-
-                // Spawn a new OS thread for sending this frame to the cloud.
-                //
-                // FIXME MAYBE: shall we switch to an async runtime and use
-                // green threads instead?
-                Some(thread::spawn(move || {
-                    render_file(&file_name, &args, ctx_args)?;
-
-                    Ok::<(), Error>(())
-                }))
-            } else {
-                if let Some(ref collective) = args.collective {
-                    ctx_args.push(nsi::string!("collective", collective.as_str()));
+            } else if let Some(ref collective) = args.collective {
+                ctx_args.push(nsi::string!("collective", collective.as_str()));
+            }
+
+            queue.push_back((file_name, frame, ctx_args));
+        });
+
+    if !missing.is_empty() {
+        return Err(RenderError::InputNotFound(missing).into());
+    }
+
+    let files = queue
+        .iter()
+        .map(|(file_name, frame, _)| (file_name.clone(), *frame))
+        .collect::<Vec<_>>();
+    let total = queue.len();
+    let outcomes = render_queue(queue, jobs, cloud, &args, remap.as_ref());
+
+    if let Some(report) = &args.report {
+        let mut render_report = RenderReport::new(&files, &outcomes, &up_to_date, &output_images);
+        for frame in &mut render_report.frames {
+            frame.input = input_name(&frame.input);
+        }
+        render_report.write(report)?;
+    }
+
+    let skipped = outcomes.iter().filter(|outcome| outcome.is_none()).count();
+    let failed = outcomes
+        .into_iter()
+        .flatten()
+        .filter(|outcome| outcome.result.is_err())
+        .collect::<Vec<_>>();
+
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    error!("{} of {total} file(s) failed:", failed.len());
+    for outcome in &failed {
+        if let Err(error) = &outcome.result {
+            error!(
+                "  '{}' after {} attempt(s): {error}",
+                input_name(&outcome.file_name),
+                outcome.attempts
+            );
+        }
+        for message in &outcome.messages {
+            if Level::Error == message.level {
+                error!("    [{}] {}", message.code, message.message);
+            }
+        }
+    }
+    if 0 < skipped {
+        warn!("{skipped} file(s) were not rendered because of --fail-fast");
+    }
+
+    Err(RenderError::FramesFailed {
+        failed: failed.len(),
+        total,
+    }
+    .into())
+}
+
+/// Returns the output images of the stream at `file_name`, or none if they
+/// can not be determined.
+fn read_output_images(
+    file_name: &str,
+    input_format: Option<&str>,
+    remap: Option<&Remap>,
+) -> Vec<String> {
+    stream_output_images(file_name, InputFormat::of(file_name, input_format), remap).unwrap_or_else(
+        |error| {
+            warn!("Could not determine output images of '{file_name}': {error}");
+            Vec::new()
+        },
+    )
+}
+
+/// Returns `true` if the stream at `file_name` writes at least one image and
+/// all of its `images` exist and are newer than the stream.
+fn outputs_up_to_date(file_name: &str, images: &[String]) -> bool {
+    let Ok(input) = fs::metadata(file_name).and_then(|metadata| metadata.modified()) else {
+        return false;
+    };
+
+    if images.is_empty() {
+        debug!("'{file_name}' has no output images to check");
+        return false;
+    }
+
+    images.iter().all(|image| {
+        fs::metadata(image)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| input < modified)
+    })
+}
+
+/// A message the renderer sent to the `errorhandler` callback.
+#[derive(Clone, Debug)]
+pub struct RenderMessage {
+    pub level: Level,
+    pub code: i32,
+    pub message: String,
+}
+
+/// The result of rendering a single file, including retries.
+pub struct RenderOutcome {
+    pub file_name: String,
+    pub attempts: usize,
+    pub result: Result<()>,
+    /// The renderer's messages from the last attempt.
+    pub messages: Vec<RenderMessage>,
+    /// When the first attempt started.
+    pub start: SystemTime,
+    /// When the last attempt ended.
+    pub end: SystemTime,
+    /// Peak resident memory of the process while rendering the file, in
+    /// bytes. Only known if files are rendered one at a time on this
+    /// machine.
+    pub peak_memory: Option<u64>,
+}
+
+/// Renders the files in `queue` on up to `jobs` threads, each with its own
+/// NSI context.
+///
+/// Files are picked up in queue order. A failed file is retried up to
+/// `args.retries` times. With `backoff`, a failed render delays picking up
+/// further files on all threads, doubling the delay on each consecutive
+/// failure up to [`MAX_BACKOFF`].
+///
+/// With `args.fail_fast` no further files are picked up once a file failed
+/// for good.
+///
+/// Returns an outcome per file in queue order; `None` for files that were not
+/// rendered.
+fn render_queue(
+    queue: VecDeque<(String, Option<isize>, nsi::ArgVec)>,
+    jobs: usize,
+    backoff: bool,
+    args: &Render,
+    remap: Option<&Remap>,
+) -> Vec<Option<RenderOutcome>> {
+    let workers = jobs.min(queue.len());
+    let outcomes = Mutex::new((0..queue.len()).map(|_| None).collect::<Vec<_>>());
+    let queue = Mutex::new(queue.into_iter().enumerate().collect::<VecDeque<_>>());
+    let backoff = backoff.then(|| Mutex::new(Backoff::default()));
+    // Concurrent frames share the process' memory, so only frames rendered
+    // one at a time get their own peak.
+    let frame_memory = !args.cloud && args.collective.is_none() && 1 == workers;
+    let stop = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                if stop.load(Ordering::Relaxed) {
+                    break;
                 }
 
-                if let Err(error) = render_file(&file_name, &args, ctx_args) {
+                let Some((index, (file_name, _, ctx_args))) = queue.lock().unwrap().pop_front()
+                else {
+                    break;
+                };
+
+                let start = SystemTime::now();
+                let measured = frame_memory && reset_peak_memory();
+                let mut attempts = 0;
+                let (result, messages) = loop {
+                    attempts += 1;
+
+                    if let Some(backoff) = &backoff {
+                        let not_before = backoff.lock().unwrap().not_before;
+                        thread::sleep(not_before.saturating_duration_since(Instant::now()));
+                    }
+
+                    let collector = Arc::new(Mutex::new(Vec::new()));
+                    let result = render_file(&file_name, args, ctx_args.clone(), remap, &collector);
+                    let messages = mem::take(&mut *collector.lock().unwrap());
+
+                    // The renderer reports some failures only through the
+                    // error handler.
+                    let errors = messages
+                        .iter()
+                        .filter(|message| Level::Error == message.level)
+                        .count();
+                    let result = result.and_then(|()| {
+                        if 0 < errors {
+                            Err(anyhow!("Renderer reported {errors} error(s)"))
+                        } else {
+                            Ok(())
+                        }
+                    });
+
+                    if let Some(backoff) = &backoff {
+                        let mut backoff = backoff.lock().unwrap();
+                        if result.is_ok() {
+                            backoff.delay = Duration::ZERO;
+                        } else {
+                            backoff.delay = (backoff.delay * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+                            backoff.not_before = Instant::now() + backoff.delay;
+                            warn!("Delaying further submissions by {:?}", backoff.delay);
+                        }
+                    }
+
+                    match result {
+                        Err(error) if attempts <= args.retries => {
+                            let delay = args.retry_delay.map_or(Duration::ZERO, |delay| {
+                                Duration::try_from_secs_f64(
+                                    delay.max(0.0) * 2f64.powi(attempts as i32 - 1),
+                                )
+                                .unwrap_or(MAX_RETRY_DELAY)
+                                .min(MAX_RETRY_DELAY)
+                            });
+                            warn!(
+                                "'{file_name}' failed (attempt {attempts} of {}), \
+                                retrying in {delay:?}: {error}",
+                                args.retries + 1
+                            );
+                            thread::sleep(delay);
+                        }
+                        result => break (result, messages),
+                    }
+                };
+
+                if let Err(error) = &result {
                     error!("{}", error);
+
+                    if args.fail_fast {
+                        stop.store(true, Ordering::Relaxed);
+                    }
                 }
 
-                None
-            }
-        })
-        // Wait for render threads to finish.
-        .for_each(|handle| {
-            if let Err(error) = handle.join().unwrap() {
-                error!("{}", error);
-            }
-        });
+                outcomes.lock().unwrap()[index] = Some(RenderOutcome {
+                    file_name,
+                    attempts,
+                    result,
+                    messages,
+                    start,
+                    end: SystemTime::now(),
+                    peak_memory: measured.then(peak_memory).flatten(),
+                });
+            });
+        }
+    });
 
-    Ok(())
+    outcomes.into_inner().unwrap()
 }
 
-pub fn render_file(file_name: &str, args: &Render, ctx_args: nsi::ArgVec) -> Result<()> {
+/// Renders `file_name` in a new NSI context created from `ctx_args`.
+///
+/// Messages from the renderer are logged and appended to `messages`.
+pub fn render_file(
+    file_name: &str,
+    args: &Render,
+    mut ctx_args: nsi::ArgVec,
+    remap: Option<&Remap>,
+    messages: &Arc<Mutex<Vec<RenderMessage>>>,
+) -> Result<()> {
+    // Nothing is evaluated, so neither a context nor lib3delight is needed.
+    if args.dry_run {
+        info!("Rendering '{file_name}'");
+        return Ok(());
+    }
+
+    let messages = messages.clone();
+    ctx_args.push(nsi::callback!(
+        "errorhandler",
+        nsi::ErrorCallback::new(move |level: Level, code: i32, message: &str| {
+            match level {
+                Level::Error => error!("[{code}] {message}"),
+                Level::Warn => warn!("[{code}] {message}"),
+                Level::Info => info!("[{code}] {message}"),
+                Level::Debug => debug!("[{code}] {message}"),
+                Level::Trace => trace!("[{code}] {message}"),
+            }
+
+            messages.lock().unwrap().push(RenderMessage {
+                level,
+                code,
+                message: message.to_string(),
+            });
+        })
+    ));
+
     let ctx = nsi::Context::new(Some(&ctx_args)).ok_or(anyhow!("Error creating NSI context."))?;
 
     if args.progress {
@@ -208,7 +656,9 @@ pub fn render_file(file_name: &str, args: &Render, ctx_args: nsi::ArgVec) -> Res
         );
     }
 
-    evaluate_file(&ctx, file_name, args.dry_run);
+    // Keep remapped copies alive until rendering has finished; procedurals
+    // may read them lazily.
+    let _remapped = evaluate_file(&ctx, file_name, args.input.input_format.as_deref(), remap)?;
 
     debug!("Done evaluating file");
 
@@ -221,22 +671,170 @@ pub fn render_file(file_name: &str, args: &Render, ctx_args: nsi::ArgVec) -> Res
     Ok(())
 }
 
-pub fn evaluate_file(ctx: &nsi::Context, file_name: &str, dry_run: bool) {
+/// Evaluates `file_name` in `ctx`.
+///
+/// The format of the file is detected unless `input_format` is given.
+pub fn evaluate_file(
+    ctx: &nsi::Context,
+    file_name: &str,
+    input_format: Option<&str>,
+    remap: Option<&Remap>,
+) -> Result<Option<RemappedStream>> {
     info!("Rendering '{}'", file_name);
 
-    if dry_run {
-        return;
-    }
+    let format = InputFormat::of(file_name, input_format);
+    debug!("Reading '{file_name}' as {format}");
+
+    let remapped = match remap {
+        Some(remap) => Some(remap.stream(file_name, format)?),
+        None => RemappedStream::decompressed(file_name)?,
+    };
 
     ctx.evaluate(&[
+        nsi::string!("type", format.evaluate_type()),
         nsi::string!(
-            "type",
-            if file_name.ends_with(".lua") {
-                "lua"
-            } else {
-                "apistream"
-            }
+            "filename",
+            remapped
+                .as_ref()
+                .map_or(file_name, |remapped| remapped.path())
         ),
-        nsi::string!("filename", file_name),
     ]);
+
+    Ok(remapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cli, Command, FrameStatus};
+    use clap::Parser;
+    use std::{env, fs::File, path::PathBuf, process};
+
+    struct Folder(PathBuf);
+
+    impl Folder {
+        fn new(name: &str) -> Self {
+            let folder = env::temp_dir().join(format!("rdl-{name}-{}", process::id()));
+            fs::create_dir_all(&folder).unwrap();
+            Self(folder)
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.0.join(name).to_string_lossy().to_string()
+        }
+    }
+
+    impl Drop for Folder {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes a stream that renders to `image`.
+    fn write_scene(path: &str, image: &str) {
+        fs::write(
+            path,
+            format!(
+                "Create \"driver\" \"outputdriver\"\nSetAttribute \"driver\" \"imagefilename\" \"string\" 1 \"{image}\"\n"
+            ),
+        )
+        .unwrap();
+    }
+
+    fn touch(path: &str, modified: SystemTime) {
+        File::create(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn checks_outputs_up_to_date() {
+        let folder = Folder::new("render-up-to-date");
+        let scene = folder.path("scene.nsi");
+        let image = folder.path("beauty.exr");
+        write_scene(&scene, &image);
+
+        let images = read_output_images(&scene, None, None);
+        assert_eq!(vec![image.clone()], images);
+        assert!(!outputs_up_to_date(&scene, &[]));
+        assert!(!outputs_up_to_date(&scene, &images));
+
+        touch(&image, SystemTime::now() - Duration::from_secs(60));
+        assert!(!outputs_up_to_date(&scene, &images));
+
+        touch(&image, SystemTime::now() + Duration::from_secs(60));
+        assert!(outputs_up_to_date(&scene, &images));
+        assert!(!outputs_up_to_date(&folder.path("missing.nsi"), &images));
+        assert!(!outputs_up_to_date(
+            &scene,
+            &[image, folder.path("albedo.exr")]
+        ));
+
+        assert_eq!(
+            Vec::<String>::new(),
+            read_output_images(&scene, Some("lua"), None)
+        );
+    }
+
+    #[test]
+    fn skips_frames_done_before() {
+        let folder = Folder::new("render-resume");
+        let [a, b, c] = ["a", "b", "c"].map(|name| folder.path(&format!("{name}.nsi")));
+        for scene in [&a, &b, &c] {
+            write_scene(scene, &scene.replace(".nsi", ".exr"));
+        }
+        // Only the image of `c` is newer than its stream.
+        touch(
+            &folder.path("c.exr"),
+            SystemTime::now() + Duration::from_secs(60),
+        );
+
+        let previous = folder.path("previous.json");
+        fs::write(
+            &previous,
+            format!(
+                r#"{{ "succeeded": 1, "failed": 1, "skipped": 0, "frames": [
+                    {{ "input": "{a}", "frame": null, "status": "succeeded", "error": null, "attempts": 1,
+                       "start": null, "end": null, "wall_time": null, "messages": [], "output_images": [],
+                       "peak_memory": null }},
+                    {{ "input": "{b}", "frame": null, "status": "failed", "error": "failed", "attempts": 1,
+                       "start": null, "end": null, "wall_time": null, "messages": [], "output_images": [],
+                       "peak_memory": null }} ] }}"#
+            ),
+        )
+        .unwrap();
+        let report = folder.path("report.json");
+
+        let Command::Render(args) = Cli::try_parse_from([
+            "rdl",
+            "render",
+            "--dry-run",
+            "--skip-existing",
+            "--resume",
+            &previous,
+            "--report",
+            &report,
+            &a,
+            &b,
+            &c,
+        ])
+        .unwrap()
+        .command
+        else {
+            panic!("not a render command");
+        };
+        render(args).unwrap();
+
+        assert_eq!(
+            vec![
+                (a, FrameStatus::UpToDate),
+                (c, FrameStatus::UpToDate),
+                (b, FrameStatus::Succeeded)
+            ],
+            RenderReport::read(&report)
+                .unwrap()
+                .frames
+                .into_iter()
+                .map(|frame| (frame.input, frame.status))
+                .collect::<Vec<_>>()
+        );
+    }
 }
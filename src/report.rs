@@ -0,0 +1,420 @@
+use crate::{output_images, stream, InputFormat, Remap, RenderOutcome, Result};
+use anyhow::anyhow;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufReader, BufWriter},
+    path::Path,
+    time::SystemTime,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FrameStatus {
+    Succeeded,
+    Failed,
+    /// Not rendered, e.g. because of `--fail-fast`.
+    Skipped,
+    /// Not rendered because of `--skip-existing` or `--resume`.
+    UpToDate,
+}
+
+impl FrameStatus {
+    /// Returns `true` if the frame does not need to be rendered again.
+    pub fn is_done(self) -> bool {
+        matches!(self, FrameStatus::Succeeded | FrameStatus::UpToDate)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageReport {
+    pub level: String,
+    pub code: i32,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FrameReport {
+    pub input: String,
+    pub frame: Option<isize>,
+    pub status: FrameStatus,
+    pub error: Option<String>,
+    pub attempts: usize,
+    /// RFC 3339 timestamp.
+    pub start: Option<String>,
+    /// RFC 3339 timestamp.
+    pub end: Option<String>,
+    /// In seconds.
+    pub wall_time: Option<f64>,
+    pub messages: Vec<MessageReport>,
+    /// Images written by the stream's output drivers during the render.
+    pub output_images: Vec<String>,
+    /// Peak resident memory of `rdl` while rendering the frame, in bytes.
+    ///
+    /// Only recorded for frames rendered one at a time on this machine.
+    pub peak_memory: Option<u64>,
+}
+
+/// A machine-readable record of an `rdl render` run.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenderReport {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    #[serde(default)]
+    pub up_to_date: usize,
+    /// Peak resident memory of `rdl` over the whole run, in bytes.
+    #[serde(default)]
+    pub run_peak_memory: Option<u64>,
+    pub frames: Vec<FrameReport>,
+}
+
+impl RenderReport {
+    /// Builds a report from the files that were queued, as `(file name,
+    /// frame)`, and their outcomes.
+    ///
+    /// Files that were `up_to_date` and not queued are listed first.
+    /// `output_images` holds the images each stream writes, by file name.
+    pub fn new(
+        files: &[(String, Option<isize>)],
+        outcomes: &[Option<RenderOutcome>],
+        up_to_date: &[(String, Option<isize>)],
+        output_images: &HashMap<String, Vec<String>>,
+    ) -> Self {
+        let frames = up_to_date
+            .iter()
+            .map(|(file_name, frame)| {
+                FrameReport::not_rendered(file_name, *frame, FrameStatus::UpToDate)
+            })
+            .chain(
+                files
+                    .iter()
+                    .zip(outcomes)
+                    .map(|((file_name, frame), outcome)| {
+                        let images = output_images.get(file_name).map_or(&[][..], Vec::as_slice);
+                        FrameReport::new(file_name, *frame, outcome.as_ref(), images)
+                    }),
+            )
+            .collect::<Vec<_>>();
+
+        let count = |status| frames.iter().filter(|frame| status == frame.status).count();
+
+        Self {
+            succeeded: count(FrameStatus::Succeeded),
+            failed: count(FrameStatus::Failed),
+            skipped: count(FrameStatus::Skipped),
+            up_to_date: count(FrameStatus::UpToDate),
+            // The process' peak is reset for each frame measured on its own.
+            run_peak_memory: frames
+                .iter()
+                .filter_map(|frame| frame.peak_memory)
+                .max()
+                .or_else(peak_memory),
+            frames,
+        }
+    }
+
+    pub fn read(path: &str) -> Result<Self> {
+        let file =
+            fs::File::open(path).map_err(|e| anyhow!("Could not open report '{path}': {e}"))?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| anyhow!("Could not parse report '{path}': {e}"))
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        let file =
+            fs::File::create(path).map_err(|e| anyhow!("Could not create report '{path}': {e}"))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)?;
+
+        info!("Wrote report '{path}'");
+
+        Ok(())
+    }
+}
+
+impl FrameReport {
+    fn not_rendered(file_name: &str, frame: Option<isize>, status: FrameStatus) -> Self {
+        Self {
+            input: file_name.to_string(),
+            frame,
+            status,
+            error: None,
+            attempts: 0,
+            start: None,
+            end: None,
+            wall_time: None,
+            messages: Vec::new(),
+            output_images: Vec::new(),
+            peak_memory: None,
+        }
+    }
+
+    fn new(
+        file_name: &str,
+        frame: Option<isize>,
+        outcome: Option<&RenderOutcome>,
+        images: &[String],
+    ) -> Self {
+        let Some(outcome) = outcome else {
+            return Self::not_rendered(file_name, frame, FrameStatus::Skipped);
+        };
+
+        Self {
+            input: file_name.to_string(),
+            frame,
+            status: if outcome.result.is_ok() {
+                FrameStatus::Succeeded
+            } else {
+                FrameStatus::Failed
+            },
+            error: outcome.result.as_ref().err().map(|error| error.to_string()),
+            attempts: outcome.attempts,
+            start: Some(humantime::format_rfc3339_millis(outcome.start).to_string()),
+            end: Some(humantime::format_rfc3339_millis(outcome.end).to_string()),
+            wall_time: outcome
+                .end
+                .duration_since(outcome.start)
+                .ok()
+                .map(|duration| duration.as_secs_f64()),
+            messages: outcome
+                .messages
+                .iter()
+                .map(|message| MessageReport {
+                    level: message.level.as_str().to_lowercase(),
+                    code: message.code,
+                    message: message.message.clone(),
+                })
+                .collect(),
+            output_images: produced_images(images, outcome.start),
+            peak_memory: outcome.peak_memory,
+        }
+    }
+}
+
+/// Returns the paths of the images the stream at `file_name` writes, with
+/// `remap` applied.
+///
+/// Lua files can not be inspected; their list is always empty.
+pub fn stream_output_images(
+    file_name: &str,
+    format: InputFormat,
+    remap: Option<&Remap>,
+) -> Result<Vec<String>> {
+    if InputFormat::Lua == format {
+        return Ok(Vec::new());
+    }
+
+    Ok(output_images(&stream::read_file(Path::new(file_name))?)
+        .into_iter()
+        .map(|image| remap.and_then(|remap| remap.apply(&image)).unwrap_or(image))
+        .collect())
+}
+
+/// Lists the `images` that were written after `since`.
+fn produced_images(images: &[String], since: SystemTime) -> Vec<String> {
+    images
+        .iter()
+        .filter(|image| {
+            fs::metadata(image)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| since <= modified)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Returns the peak resident memory of this process, in bytes.
+#[cfg(target_os = "linux")]
+pub fn peak_memory() -> Option<u64> {
+    fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(|kib| kib * 1024)
+}
+
+/// Returns the peak resident memory of this process, in bytes.
+#[cfg(not(target_os = "linux"))]
+pub fn peak_memory() -> Option<u64> {
+    None
+}
+
+/// Resets the peak resident memory of this process to the current one.
+///
+/// Returns `false` if that is not possible.
+#[cfg(target_os = "linux")]
+pub fn reset_peak_memory() -> bool {
+    fs::write("/proc/self/clear_refs", "5").is_ok()
+}
+
+/// Resets the peak resident memory of this process to the current one.
+///
+/// Returns `false` if that is not possible.
+#[cfg(not(target_os = "linux"))]
+pub fn reset_peak_memory() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RenderMessage;
+    use log::Level;
+    use std::{env, process, time::Duration};
+
+    fn outcome(result: Result<()>, start: SystemTime) -> Option<RenderOutcome> {
+        Some(RenderOutcome {
+            file_name: String::new(),
+            attempts: 2,
+            result,
+            messages: vec![RenderMessage {
+                level: Level::Warn,
+                code: 42,
+                message: "Low on memory".into(),
+            }],
+            start,
+            end: start + Duration::from_millis(1500),
+            peak_memory: Some(1024),
+        })
+    }
+
+    #[test]
+    fn marks_done_frames() {
+        assert!(FrameStatus::Succeeded.is_done());
+        assert!(FrameStatus::UpToDate.is_done());
+        assert!(!FrameStatus::Failed.is_done());
+        assert!(!FrameStatus::Skipped.is_done());
+    }
+
+    #[test]
+    fn reports_frames() {
+        let folder = env::temp_dir().join(format!("rdl-report-frames-{}", process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let scene = "scene.nsi".to_string();
+        let image = folder.join("beauty.exr").to_string_lossy().to_string();
+        // Only images written since the render started are listed.
+        let output_images = HashMap::from([(
+            scene.clone(),
+            vec![
+                image.clone(),
+                folder.join("missing.exr").to_string_lossy().to_string(),
+            ],
+        )]);
+
+        let start = SystemTime::now() - Duration::from_secs(10);
+        fs::write(&image, "").unwrap();
+
+        let report = RenderReport::new(
+            &[
+                (scene.clone(), Some(2)),
+                (scene.clone(), Some(3)),
+                (scene.clone(), Some(4)),
+            ],
+            &[
+                outcome(Ok(()), start),
+                outcome(Err(anyhow!("Render failed")), start),
+                None,
+            ],
+            &[(scene.clone(), Some(1))],
+            &output_images,
+        );
+
+        assert_eq!(
+            (1, 1, 1, 1),
+            (
+                report.succeeded,
+                report.failed,
+                report.skipped,
+                report.up_to_date
+            )
+        );
+        assert_eq!(Some(1024), report.run_peak_memory);
+        assert_eq!(
+            vec![
+                (Some(1), FrameStatus::UpToDate),
+                (Some(2), FrameStatus::Succeeded),
+                (Some(3), FrameStatus::Failed),
+                (Some(4), FrameStatus::Skipped)
+            ],
+            report
+                .frames
+                .iter()
+                .map(|frame| (frame.frame, frame.status))
+                .collect::<Vec<_>>()
+        );
+
+        let succeeded = &report.frames[1];
+        assert_eq!(scene, succeeded.input);
+        assert_eq!(2, succeeded.attempts);
+        assert_eq!(None, succeeded.error);
+        assert_eq!(Some(1.5), succeeded.wall_time);
+        assert_eq!(
+            ("warn", 42, "Low on memory"),
+            (
+                succeeded.messages[0].level.as_str(),
+                succeeded.messages[0].code,
+                succeeded.messages[0].message.as_str()
+            )
+        );
+        assert_eq!(vec![image], succeeded.output_images);
+        assert_eq!(Some(1024), succeeded.peak_memory);
+
+        assert_eq!(Some("Render failed".into()), report.frames[2].error);
+        assert_eq!(
+            (0, None, None),
+            (
+                report.frames[3].attempts,
+                report.frames[3].start.as_ref(),
+                report.frames[3].peak_memory
+            )
+        );
+
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn round_trips_json() {
+        let path = env::temp_dir().join(format!("rdl-report-{}.json", process::id()));
+        let path = path.to_str().unwrap();
+
+        let report = RenderReport::new(
+            &[("a.nsi".into(), None), ("b.nsi".into(), None)],
+            &[outcome(Ok(()), SystemTime::UNIX_EPOCH), None],
+            &[("c.nsi".into(), None)],
+            &HashMap::new(),
+        );
+        report.write(path).unwrap();
+        let json = fs::read_to_string(path).unwrap();
+        assert!(json.contains("\"status\": \"up-to-date\""));
+        assert!(json.contains("\"start\": \"1970-01-01T00:00:00.000Z\""));
+
+        let read = RenderReport::read(path).unwrap();
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::to_value(&read).unwrap()
+        );
+
+        // Reports of earlier versions lack the up-to-date count and peak
+        // memory.
+        fs::write(
+            path,
+            r#"{ "succeeded": 0, "failed": 0, "skipped": 0, "frames": [] }"#,
+        )
+        .unwrap();
+        let read = RenderReport::read(path).unwrap();
+        assert_eq!((0, None), (read.up_to_date, read.run_peak_memory));
+
+        fs::remove_file(path).unwrap();
+        assert!(RenderReport::read(path)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Could not open report"));
+    }
+}
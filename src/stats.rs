@@ -0,0 +1,280 @@
+use crate::{
+    is_output_image,
+    stream::{self, Command, Statement, Value},
+    Result, Stats,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::Path,
+};
+
+const TEXTURE_EXTENSIONS: &[&str] = &[
+    "tdl", "tx", "tex", "exr", "hdr", "tif", "tiff", "png", "jpg", "jpeg", "tga",
+];
+
+/// Summary of the contents of an NSI stream.
+#[derive(Debug, Default, Serialize)]
+pub struct SceneStats {
+    pub nodes: BTreeMap<String, usize>,
+    pub mesh_vertices: usize,
+    pub mesh_faces: usize,
+    pub lights: usize,
+    pub shaders: BTreeSet<String>,
+    pub textures: BTreeSet<String>,
+    /// Estimated size of all attribute data, in bytes.
+    pub attribute_bytes: usize,
+}
+
+pub fn stats(args: Stats) -> Result<()> {
+    let statements = stream::read_file(Path::new(&args.file))?;
+    let stats = scene_stats(&statements);
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    println!("{:<24}{:>12}", "Node type", "Count");
+    for (node_type, count) in &stats.nodes {
+        println!("{node_type:<24}{count:>12}");
+    }
+    println!();
+    println!("{:<24}{:>12}", "Mesh vertices", stats.mesh_vertices);
+    println!("{:<24}{:>12}", "Mesh faces", stats.mesh_faces);
+    println!("{:<24}{:>12}", "Lights", stats.lights);
+    println!(
+        "{:<24}{:>12}",
+        "Attribute data",
+        human_bytes(stats.attribute_bytes)
+    );
+
+    for (title, paths) in [("Shaders", &stats.shaders), ("Textures", &stats.textures)] {
+        println!();
+        println!("{title} ({})", paths.len());
+        for path in paths {
+            println!("  {path}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Gathers [`SceneStats`] from a parsed stream.
+pub fn scene_stats(statements: &[Statement]) -> SceneStats {
+    let mut stats = SceneStats::default();
+
+    let mut node_types = HashMap::<&str, &str>::new();
+    // (handle, attribute, time) → bytes
+    let mut attribute_bytes = HashMap::<(&str, &str, u64), usize>::new();
+    // handle → (vertices, faces)
+    let mut meshes = HashMap::<&str, (usize, usize)>::new();
+
+    for Statement { command, .. } in statements {
+        match command {
+            Command::Create {
+                handle, node_type, ..
+            } => {
+                node_types.insert(handle, node_type);
+            }
+            Command::Delete { handle, .. } => {
+                node_types.remove(handle.as_str());
+                meshes.remove(handle.as_str());
+                attribute_bytes.retain(|(h, _, _), _| h != handle);
+            }
+            Command::SetAttribute { handle, args }
+            | Command::SetAttributeAtTime { handle, args, .. } => {
+                let time = match command {
+                    Command::SetAttributeAtTime { time, .. } => time.to_bits(),
+                    _ => 0,
+                };
+                let node_type = node_types.get(handle.as_str()).copied().unwrap_or_default();
+
+                for arg in args {
+                    attribute_bytes.insert((handle, &arg.name, time), value_bytes(&arg.value));
+
+                    if "mesh" == node_type {
+                        let mesh = meshes.entry(handle).or_default();
+                        match arg.name.as_str() {
+                            "P" => mesh.0 = mesh.0.max(arg.count),
+                            "nvertices" => mesh.1 = mesh.1.max(arg.count),
+                            _ => (),
+                        }
+                    }
+
+                    if let Value::Strings(strings) = &arg.value {
+                        if "shader" == node_type && "shaderfilename" == arg.name {
+                            stats.shaders.extend(strings.iter().cloned());
+                        } else if !is_output_image(Some(node_type), arg) {
+                            stats
+                                .textures
+                                .extend(strings.iter().filter(|s| is_texture(s)).cloned());
+                        }
+                    }
+                }
+            }
+            Command::DeleteAttribute { handle, name } => {
+                attribute_bytes.retain(|(h, n, _), _| h != handle || n != name);
+            }
+            _ => (),
+        }
+    }
+
+    for node_type in node_types.values() {
+        *stats.nodes.entry(node_type.to_string()).or_default() += 1;
+    }
+
+    let light_shaders = statements
+        .iter()
+        .filter_map(|statement| match &statement.command {
+            Command::SetAttribute { handle, args }
+            | Command::SetAttributeAtTime { handle, args, .. } => args
+                .iter()
+                .find(|arg| "shaderfilename" == arg.name)
+                .and_then(|arg| arg.as_str())
+                .filter(|file| is_light_shader(file))
+                .map(|_| handle.as_str()),
+            _ => None,
+        })
+        .filter(|handle| Some(&"shader") == node_types.get(handle))
+        .collect::<BTreeSet<_>>();
+    stats.lights = light_shaders.len() + stats.nodes.get("environment").copied().unwrap_or(0);
+
+    stats.mesh_vertices = meshes.values().map(|(vertices, _)| vertices).sum();
+    stats.mesh_faces = meshes.values().map(|(_, faces)| faces).sum();
+    stats.attribute_bytes = attribute_bytes.values().sum();
+
+    stats
+}
+
+/// Returns `true` if the shader file name of a `shader` node suggests it is a
+/// light, e.g. `pointLight.oso` or `dlAreaLight`.
+///
+/// OSL has no notion of lights; any surface that emits light is one. So this
+/// is a guess that misses emissive materials and counts shaders named e.g.
+/// `highlight.oso`.
+fn is_light_shader(file: &str) -> bool {
+    Path::new(file)
+        .file_stem()
+        .is_some_and(|stem| stem.to_string_lossy().to_lowercase().ends_with("light"))
+}
+
+/// Returns `true` if `path` has the extension of an image/texture file.
+pub fn is_texture(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| TEXTURE_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+fn value_bytes(value: &Value) -> usize {
+    match value {
+        Value::Integers(v) => v.len() * std::mem::size_of::<i32>(),
+        Value::Floats(v) => v.len() * std::mem::size_of::<f32>(),
+        Value::Doubles(v) => v.len() * std::mem::size_of::<f64>(),
+        // Pointer to a NUL-terminated string.
+        Value::Strings(v) => v
+            .iter()
+            .map(|s| std::mem::size_of::<usize>() + s.len() + 1)
+            .sum(),
+    }
+}
+
+fn human_bytes(bytes: usize) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while 1024.0 <= size && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if 0 == unit {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = r#"Create "m1" "mesh"
+SetAttribute "m1"
+  "nvertices" "int" 2 [ 4 4 ]
+  "P" "point" 6 [ 0 0 0  1 0 0  1 1 0  0 1 0  2 0 0  2 1 0 ]
+SetAttributeAtTime "m1" 1
+  "P" "point" 6 [ 0 0 1  1 0 1  1 1 1  0 1 1  2 0 1  2 1 1 ]
+Create "m2" "mesh"
+SetAttribute "m2" "nvertices" "int" 1 3 "P" "point" 3 [ 0 0 0  1 0 0  1 1 0 ]
+Create "key" "shader"
+SetAttributeAtTime "key" 0 "shaderfilename" "string" 1 "${DELIGHT}/osl/pointLight.oso"
+Create "blocker" "shader"
+SetAttribute "blocker"
+  "shaderfilename" "string" 1 "lightBlocker.oso"
+  "map" "string" 1 "wood.TDL"
+Create "env" "environment"
+Create "x" "transform"
+SetAttribute "x" "shaderfilename" "string" 1 "spotLight.oso"
+Create "gone" "mesh"
+SetAttribute "gone" "P" "point" 1 [ 0 0 0 ]
+Delete "gone"
+Create "driver" "outputdriver"
+SetAttribute "driver" "imagefilename" "string" 1 "beauty.exr"
+"#;
+
+    #[test]
+    fn counts_scene_contents() {
+        let stats = scene_stats(&stream::parse(SCENE).unwrap());
+
+        assert_eq!(
+            BTreeMap::from([
+                ("environment".into(), 1),
+                ("mesh".into(), 2),
+                ("outputdriver".into(), 1),
+                ("shader".into(), 2),
+                ("transform".into(), 1),
+            ]),
+            stats.nodes
+        );
+        assert_eq!((9, 3), (stats.mesh_vertices, stats.mesh_faces));
+        // The environment and the point light; neither the light blocker nor
+        // a shader file name on a transform count.
+        assert_eq!(2, stats.lights);
+        assert_eq!(
+            BTreeSet::from([
+                "${DELIGHT}/osl/pointLight.oso".into(),
+                "lightBlocker.oso".into()
+            ]),
+            stats.shaders
+        );
+        // The output image is not a texture.
+        assert_eq!(BTreeSet::from(["wood.TDL".into()]), stats.textures);
+        // Both time samples of "P" count; the deleted node does not.
+        assert_eq!(
+            8 + 72 + 72 + 4 + 36 + 38 + 25 + 17 + 22 + 19,
+            stats.attribute_bytes
+        );
+    }
+
+    #[test]
+    fn guesses_light_shaders() {
+        assert!(is_light_shader("pointLight.oso"));
+        assert!(is_light_shader("/osl/dlAreaLight"));
+        assert!(is_light_shader("SPOTLIGHT.OSO"));
+        assert!(!is_light_shader("lightBlocker.oso"));
+        assert!(!is_light_shader("/lights/dlPrincipled.oso"));
+    }
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!("0 B", human_bytes(0));
+        assert_eq!("1023 B", human_bytes(1023));
+        assert_eq!("1.0 KiB", human_bytes(1024));
+        assert_eq!("1.5 KiB", human_bytes(1536));
+        assert_eq!("5.0 GiB", human_bytes(5 << 30));
+        assert_eq!("2048.0 TiB", human_bytes(1 << 51));
+    }
+}
@@ -0,0 +1,663 @@
+//! Tokenizer, parser and writer for the ASCII NSI encoding.
+use super::*;
+use std::{error::Error, io};
+
+/// An error encountered while parsing an ASCII NSI stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token<'a> {
+    Word(&'a str),
+    Str(String),
+    Number(&'a str),
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(w) => write!(f, "`{w}`"),
+            Token::Str(s) => write!(f, "string \"{s}\""),
+            Token::Number(n) => write!(f, "number `{n}`"),
+            Token::Open => f.write_str("`[`"),
+            Token::Close => f.write_str("`]`"),
+        }
+    }
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    offset: usize,
+    line: usize,
+    column: usize,
+    peeked: Option<(Token<'a>, Span)>,
+    /// End of the last token handed out by `next()`.
+    last_end: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            offset: 0,
+            line: 1,
+            column: 1,
+            peeked: None,
+            last_end: 0,
+        }
+    }
+
+    fn error(&self, span: Span, message: impl Into<String>) -> ParseError {
+        ParseError {
+            message: message.into(),
+            line: span.line,
+            column: span.column,
+        }
+    }
+
+    fn position(&self) -> Span {
+        Span {
+            start: self.offset,
+            end: self.offset,
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.source[self.offset..].chars().next()?;
+        self.offset += c.len_utf8();
+        if '\n' == c {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn current(&self) -> Option<char> {
+        self.source[self.offset..].chars().next()
+    }
+
+    fn skip_whitespace_and_comments(&mut self) {
+        while let Some(c) = self.current() {
+            if c.is_whitespace() {
+                self.bump();
+            } else if '#' == c {
+                while let Some(c) = self.bump() {
+                    if '\n' == c {
+                        break;
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Result<Option<&(Token<'a>, Span)>, ParseError> {
+        if self.peeked.is_none() {
+            self.peeked = self.lex()?;
+        }
+        Ok(self.peeked.as_ref())
+    }
+
+    fn next(&mut self) -> Result<Option<(Token<'a>, Span)>, ParseError> {
+        let token = match self.peeked.take() {
+            Some(token) => Some(token),
+            None => self.lex()?,
+        };
+        if let Some((_, span)) = &token {
+            self.last_end = span.end;
+        }
+        Ok(token)
+    }
+
+    fn lex(&mut self) -> Result<Option<(Token<'a>, Span)>, ParseError> {
+        self.skip_whitespace_and_comments();
+
+        let mut span = self.position();
+
+        let token = match self.current() {
+            None => return Ok(None),
+            Some('[') => {
+                self.bump();
+                Token::Open
+            }
+            Some(']') => {
+                self.bump();
+                Token::Close
+            }
+            Some('"') => {
+                self.bump();
+                let mut string = String::new();
+                loop {
+                    match self.bump() {
+                        None => return Err(self.error(span, "Unterminated string")),
+                        Some('"') => break,
+                        Some('\\') => match self.bump() {
+                            Some('n') => string.push('\n'),
+                            Some('t') => string.push('\t'),
+                            Some(c) => string.push(c),
+                            None => return Err(self.error(span, "Unterminated string")),
+                        },
+                        Some(c) => string.push(c),
+                    }
+                }
+                Token::Str(string)
+            }
+            Some(c) => {
+                let start = self.offset;
+                while let Some(c) = self.current() {
+                    if c.is_whitespace() || '[' == c || ']' == c || '"' == c || '#' == c {
+                        break;
+                    }
+                    self.bump();
+                }
+                let word = &self.source[start..self.offset];
+                if c.is_ascii_digit() || '-' == c || '+' == c || '.' == c {
+                    Token::Number(word)
+                } else {
+                    Token::Word(word)
+                }
+            }
+        };
+
+        span.end = self.offset;
+
+        Ok(Some((token, span)))
+    }
+
+    fn expect_string(&mut self, what: &str) -> Result<String, ParseError> {
+        self.expect_spanned_string(what).map(|(s, _)| s)
+    }
+
+    fn expect_spanned_string(&mut self, what: &str) -> Result<(String, Span), ParseError> {
+        match self.next()? {
+            Some((Token::Str(s), span)) => Ok((s, span)),
+            Some((token, span)) => Err(self.error(span, format!("Expected {what}, found {token}"))),
+            None => Err(self.error(
+                self.position(),
+                format!("Expected {what}, found end of stream"),
+            )),
+        }
+    }
+
+    fn expect_number(&mut self, what: &str) -> Result<(&'a str, Span), ParseError> {
+        match self.next()? {
+            Some((Token::Number(n), span)) => Ok((n, span)),
+            Some((token, span)) => Err(self.error(span, format!("Expected {what}, found {token}"))),
+            None => Err(self.error(
+                self.position(),
+                format!("Expected {what}, found end of stream"),
+            )),
+        }
+    }
+}
+
+/// Parses an ASCII NSI stream into a list of [`Statement`]s.
+pub fn parse(source: &str) -> Result<Vec<Statement>, ParseError> {
+    let mut lexer = Lexer::new(source);
+    let mut statements = Vec::new();
+
+    while let Some((token, mut span)) = lexer.next()? {
+        let command = match token {
+            Token::Word(word) => parse_command(&mut lexer, word, span)?,
+            token => return Err(lexer.error(span, format!("Expected command, found {token}"))),
+        };
+
+        span.end = lexer.last_end;
+        statements.push(Statement { command, span });
+    }
+
+    Ok(statements)
+}
+
+fn parse_command(lexer: &mut Lexer, word: &str, span: Span) -> Result<Command, ParseError> {
+    Ok(match word {
+        "Create" => Command::Create {
+            handle: lexer.expect_string("handle")?,
+            node_type: lexer.expect_string("node type")?,
+            args: parse_args(lexer)?,
+        },
+        "Delete" => Command::Delete {
+            handle: lexer.expect_string("handle")?,
+            args: parse_args(lexer)?,
+        },
+        "SetAttribute" => Command::SetAttribute {
+            handle: lexer.expect_string("handle")?,
+            args: parse_args(lexer)?,
+        },
+        "SetAttributeAtTime" => {
+            let handle = lexer.expect_string("handle")?;
+            let (time, time_span) = lexer.expect_number("time")?;
+            Command::SetAttributeAtTime {
+                handle,
+                time: time
+                    .parse()
+                    .map_err(|_| lexer.error(time_span, format!("Invalid time `{time}`")))?,
+                args: parse_args(lexer)?,
+            }
+        }
+        "DeleteAttribute" => Command::DeleteAttribute {
+            handle: lexer.expect_string("handle")?,
+            name: lexer.expect_string("attribute name")?,
+        },
+        "Connect" => Command::Connect {
+            from: lexer.expect_string("source handle")?,
+            from_attr: lexer.expect_string("source attribute")?,
+            to: lexer.expect_string("target handle")?,
+            to_attr: lexer.expect_string("target attribute")?,
+            args: parse_args(lexer)?,
+        },
+        "Disconnect" => Command::Disconnect {
+            from: lexer.expect_string("source handle")?,
+            from_attr: lexer.expect_string("source attribute")?,
+            to: lexer.expect_string("target handle")?,
+            to_attr: lexer.expect_string("target attribute")?,
+        },
+        "Evaluate" => Command::Evaluate {
+            args: parse_args(lexer)?,
+        },
+        "RenderControl" => Command::RenderControl {
+            args: parse_args(lexer)?,
+        },
+        _ => return Err(lexer.error(span, format!("Unknown command `{word}`"))),
+    })
+}
+
+fn parse_args(lexer: &mut Lexer) -> Result<Vec<Arg>, ParseError> {
+    let mut args = Vec::new();
+
+    while let Some((Token::Str(_), _)) = lexer.peek()? {
+        args.push(parse_arg(lexer)?);
+    }
+
+    Ok(args)
+}
+
+/// Splits `type[n]` into the type and the optional array length.
+fn parse_type(declaration: &str) -> Option<(Type, Option<usize>)> {
+    let declaration = declaration.trim();
+    match declaration.find('[') {
+        Some(pos) => {
+            let len = declaration[pos + 1..]
+                .strip_suffix(']')?
+                .trim()
+                .parse()
+                .ok()?;
+            Some((Type::from_name(declaration[..pos].trim())?, Some(len)))
+        }
+        None => Some((Type::from_name(declaration)?, None)),
+    }
+}
+
+fn parse_arg(lexer: &mut Lexer) -> Result<Arg, ParseError> {
+    let name = lexer.expect_string("argument name")?;
+
+    let (declaration, declaration_span) = lexer.expect_spanned_string("argument type")?;
+    let (data_type, array_len) = parse_type(&declaration).ok_or_else(|| {
+        lexer.error(
+            declaration_span,
+            format!("Invalid type `{declaration}` for argument \"{name}\""),
+        )
+    })?;
+
+    let (count, count_span) = lexer.expect_number("argument count")?;
+    let count = count
+        .parse::<usize>()
+        .map_err(|_| lexer.error(count_span, format!("Invalid count `{count}`")))?;
+
+    let mut arg = Arg {
+        name,
+        data_type,
+        array_len,
+        count,
+        value: match data_type {
+            Type::Integer => Value::Integers(Vec::new()),
+            Type::Double | Type::DoubleMatrix => Value::Doubles(Vec::new()),
+            Type::String => Value::Strings(Vec::new()),
+            _ => Value::Floats(Vec::new()),
+        },
+    };
+    let expected = arg.scalar_count();
+
+    let bracketed = matches!(lexer.peek()?, Some((Token::Open, _)));
+    if bracketed {
+        lexer.next()?;
+    }
+
+    loop {
+        if bracketed {
+            if let Some((Token::Close, _)) = lexer.peek()? {
+                lexer.next()?;
+                break;
+            }
+        } else if arg.value.len() == expected {
+            break;
+        }
+
+        let (token, span) = lexer
+            .next()?
+            .ok_or_else(|| lexer.error(lexer.position(), "Unexpected end of stream"))?;
+
+        match (&mut arg.value, token) {
+            (Value::Strings(v), Token::Str(s)) => v.push(s),
+            (Value::Integers(v), Token::Number(n)) => v.push(
+                n.parse()
+                    .map_err(|_| lexer.error(span, format!("Invalid integer `{n}`")))?,
+            ),
+            (Value::Floats(v), Token::Number(n)) => v.push(
+                n.parse()
+                    .map_err(|_| lexer.error(span, format!("Invalid float `{n}`")))?,
+            ),
+            (Value::Doubles(v), Token::Number(n)) => v.push(
+                n.parse()
+                    .map_err(|_| lexer.error(span, format!("Invalid double `{n}`")))?,
+            ),
+            (_, token) => {
+                return Err(lexer.error(
+                    span,
+                    format!(
+                        "Unexpected {token} in value of {} argument \"{}\"",
+                        data_type, arg.name
+                    ),
+                ))
+            }
+        }
+    }
+
+    if arg.value.len() != expected {
+        return Err(lexer.error(
+            declaration_span,
+            format!(
+                "Argument \"{}\" declares {expected} value(s) but has {}",
+                arg.name,
+                arg.value.len()
+            ),
+        ));
+    }
+
+    Ok(arg)
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\t' => f.write_str("\\t")?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+fn write_values<T: fmt::Display>(f: &mut fmt::Formatter<'_>, values: &[T]) -> fmt::Result {
+    for (i, value) in values.iter().enumerate() {
+        if 0 != i {
+            f.write_str(" ")?;
+        }
+        write!(f, "{value}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_string(f, &self.name)?;
+        match self.array_len {
+            Some(len) => write!(f, " \"{}[{len}]\" {} ", self.data_type, self.count)?,
+            None => write!(f, " \"{}\" {} ", self.data_type, self.count)?,
+        }
+
+        let bracketed = 1 != self.value.len();
+        if bracketed {
+            f.write_str("[ ")?;
+        }
+        match &self.value {
+            Value::Integers(v) => write_values(f, v)?,
+            Value::Floats(v) => write_values(f, v)?,
+            Value::Doubles(v) => write_values(f, v)?,
+            Value::Strings(v) => {
+                for (i, s) in v.iter().enumerate() {
+                    if 0 != i {
+                        f.write_str(" ")?;
+                    }
+                    write_string(f, s)?;
+                }
+            }
+        }
+        if bracketed {
+            f.write_str(" ]")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())?;
+
+        let strings: &[&String] = match self {
+            Command::Create {
+                handle, node_type, ..
+            } => &[handle, node_type],
+            Command::Delete { handle, .. }
+            | Command::SetAttribute { handle, .. }
+            | Command::SetAttributeAtTime { handle, .. } => &[handle],
+            Command::DeleteAttribute { handle, name } => &[handle, name],
+            Command::Connect {
+                from,
+                from_attr,
+                to,
+                to_attr,
+                ..
+            }
+            | Command::Disconnect {
+                from,
+                from_attr,
+                to,
+                to_attr,
+            } => &[from, from_attr, to, to_attr],
+            Command::Evaluate { .. } | Command::RenderControl { .. } => &[],
+        };
+        for s in strings {
+            f.write_str(" ")?;
+            write_string(f, s)?;
+        }
+
+        if let Command::SetAttributeAtTime { time, .. } = self {
+            write!(f, " {time}")?;
+        }
+
+        for arg in self.args() {
+            f.write_str("\n  ")?;
+            arg.fmt(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes commands as an ASCII NSI stream.
+pub fn write_ascii<'a>(
+    writer: &mut impl io::Write,
+    commands: impl IntoIterator<Item = &'a Command>,
+) -> io::Result<()> {
+    for command in commands {
+        writeln!(writer, "{command}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = r#"# A comment
+Create "mesh1" "mesh"
+SetAttribute "mesh1"
+  "nvertices" "int" 1 4
+  "P" "point" 4 [ 0 0 0  1 0 0  1 1 0  0 1 0 ]
+  "uv" "float[2]" 1 [ 0.5 0.25 ]
+SetAttributeAtTime "xform" 0.5
+  "transformationmatrix" "doublematrix" 1 [
+    1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1 ]
+Connect "mesh1" "" "xform" "objects" "strength" "double" 1 2.5
+Disconnect "a" "b" "c" "d"
+DeleteAttribute "mesh1" "uv"
+Evaluate "filename" "string" 1 "in \"quotes\"\n" "type" "string" 1 "apistream"
+RenderControl "action" "string" 1 "start"
+"#;
+
+    fn commands(source: &str) -> Vec<Command> {
+        parse(source)
+            .unwrap()
+            .into_iter()
+            .map(|statement| statement.command)
+            .collect()
+    }
+
+    #[test]
+    fn parses_commands() {
+        let commands = commands(SCENE);
+
+        assert_eq!(8, commands.len());
+        assert_eq!(
+            Command::Create {
+                handle: "mesh1".into(),
+                node_type: "mesh".into(),
+                args: Vec::new(),
+            },
+            commands[0]
+        );
+        assert_eq!(
+            Command::DeleteAttribute {
+                handle: "mesh1".into(),
+                name: "uv".into(),
+            },
+            commands[5]
+        );
+        assert!(matches!(
+            &commands[2],
+            Command::SetAttributeAtTime { handle, time, .. } if "xform" == handle && 0.5 == *time
+        ));
+    }
+
+    #[test]
+    fn parses_args() {
+        let commands = commands(SCENE);
+        let args = commands[1].args();
+
+        assert_eq!(3, args.len());
+        assert_eq!(Value::Integers(vec![4]), args[0].value);
+        assert_eq!((Type::Point, 4, 12), {
+            let arg = &args[1];
+            (arg.data_type, arg.count, arg.value.len())
+        });
+        assert_eq!(Some(2), args[2].array_len);
+        assert_eq!(Value::Floats(vec![0.5, 0.25]), args[2].value);
+
+        assert_eq!(16, commands[2].args()[0].value.len());
+        assert_eq!(Value::Doubles(vec![2.5]), commands[3].args()[0].value);
+        assert_eq!(Some("in \"quotes\"\n"), commands[6].args()[0].as_str());
+    }
+
+    #[test]
+    fn records_spans() {
+        let statements = parse(SCENE).unwrap();
+
+        assert_eq!((2, 1), (statements[0].span.line, statements[0].span.column));
+        assert_eq!((3, 1), (statements[1].span.line, statements[1].span.column));
+        assert_eq!(
+            "Create \"mesh1\" \"mesh\"",
+            &SCENE[statements[0].span.start..statements[0].span.end]
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_location() {
+        let error = |source| parse(source).unwrap_err();
+
+        assert_eq!(
+            "2:1: Unknown command `Crate`",
+            error("Create \"a\" \"mesh\"\nCrate \"b\" \"mesh\"").to_string()
+        );
+        assert_eq!(
+            "1:22: Argument \"P\" declares 6 value(s) but has 3",
+            error("SetAttribute \"a\" \"P\" \"point\" 2 [ 0 0 0 ]").to_string()
+        );
+        assert_eq!(
+            "1:18: Unterminated string",
+            error("SetAttribute \"a\" \"b").to_string()
+        );
+        assert_eq!(
+            "1:1: Expected command, found `[`",
+            error("[WARN] no context").to_string()
+        );
+        assert_eq!(
+            "1:14: Invalid type `vec3` for argument \"v\"",
+            error("Evaluate \"v\" \"vec3\" 1 0").to_string()
+        );
+    }
+
+    #[test]
+    fn writes_ascii() {
+        let command = Command::SetAttribute {
+            handle: "a".into(),
+            args: vec![
+                Arg {
+                    name: "name".into(),
+                    data_type: Type::String,
+                    array_len: None,
+                    count: 1,
+                    value: Value::Strings(vec!["tab\tand \\".into()]),
+                },
+                Arg {
+                    name: "uv".into(),
+                    data_type: Type::Float,
+                    array_len: Some(2),
+                    count: 1,
+                    value: Value::Floats(vec![0.5, 1.0]),
+                },
+            ],
+        };
+
+        assert_eq!(
+            "SetAttribute \"a\"\n  \"name\" \"string\" 1 \"tab\\tand \\\\\"\n  \
+             \"uv\" \"float[2]\" 1 [ 0.5 1 ]",
+            command.to_string()
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let commands = commands(SCENE);
+
+        let mut written = Vec::new();
+        write_ascii(&mut written, &commands).unwrap();
+
+        assert_eq!(
+            commands,
+            self::commands(std::str::from_utf8(&written).unwrap())
+        );
+    }
+}
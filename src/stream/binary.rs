@@ -0,0 +1,539 @@
+//! Reader and writer for the binary NSI encoding of rdl.
+//!
+//! The `binarynsi` encoding lib3delight writes is not documented, so rdl uses
+//! its own, which it can read and write without 3Delight. A stream starts
+//! with [`MAGIC`] followed by a sequence of commands. All numbers are little
+//! endian.
+//!
+//! ```text
+//! command := opcode:u8 string* [time:f64] [args]
+//! string  := length:u32 utf8-bytes
+//! args    := arg-count:u32 arg*
+//! arg     := name:string type:u8 array-length:u32 count:u32 scalar*
+//! scalar  := i32 | f32 | f64 | string
+//! ```
+//!
+//! The number of strings following the opcode is the same as in the ASCII
+//! encoding (e.g. two for `Create`, four for `Connect`). An array length of
+//! zero means the argument is not an array.
+//!
+//! Binary streams without [`MAGIC`], e.g. written by lib3delight, are
+//! converted to ASCII by lib3delight, if installed.
+use super::*;
+use anyhow::anyhow;
+use std::{error::Error, io};
+
+/// Identifies a binary NSI stream written by rdl.
+pub const MAGIC: &[u8; 5] = b"NSI\0\x01";
+
+/// Number of bytes checked by [`is_binary()`].
+const SNIFF_LEN: usize = 4096;
+
+/// An error encountered while decoding a binary NSI stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    pub message: String,
+    /// Byte offset into the stream.
+    pub offset: usize,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "byte {}: {}", self.offset, self.message)
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Returns `true` if `bytes` are a binary NSI stream, written by rdl or
+/// lib3delight.
+///
+/// ASCII NSI streams are text and never contain NUL bytes; binary ones
+/// encode numbers and lengths as raw bytes and practically always do.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.iter().take(SNIFF_LEN).any(|&byte| 0 == byte)
+}
+
+/// Returns `true` if `bytes` start with the binary NSI [`MAGIC`] of rdl.
+pub fn has_magic(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn opcode(command: &Command) -> u8 {
+    match command {
+        Command::Create { .. } => 1,
+        Command::Delete { .. } => 2,
+        Command::SetAttribute { .. } => 3,
+        Command::SetAttributeAtTime { .. } => 4,
+        Command::DeleteAttribute { .. } => 5,
+        Command::Connect { .. } => 6,
+        Command::Disconnect { .. } => 7,
+        Command::Evaluate { .. } => 8,
+        Command::RenderControl { .. } => 9,
+    }
+}
+
+fn type_code(data_type: Type) -> u8 {
+    match data_type {
+        Type::Float => 1,
+        Type::Double => 2,
+        Type::Integer => 3,
+        Type::String => 4,
+        Type::Color => 5,
+        Type::Point => 6,
+        Type::Vector => 7,
+        Type::Normal => 8,
+        Type::Matrix => 9,
+        Type::DoubleMatrix => 10,
+    }
+}
+
+fn type_from_code(code: u8) -> Option<Type> {
+    Some(match code {
+        1 => Type::Float,
+        2 => Type::Double,
+        3 => Type::Integer,
+        4 => Type::String,
+        5 => Type::Color,
+        6 => Type::Point,
+        7 => Type::Vector,
+        8 => Type::Normal,
+        9 => Type::Matrix,
+        10 => Type::DoubleMatrix,
+        _ => return None,
+    })
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn error(&self, message: impl Into<String>) -> DecodeError {
+        DecodeError {
+            message: message.into(),
+            offset: self.offset,
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset.saturating_add(len))
+            .ok_or_else(|| self.error("Unexpected end of stream"))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, DecodeError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, DecodeError> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, DecodeError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.u32()? as usize;
+        let start = self.offset;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| DecodeError {
+            message: "Invalid UTF-8 in string".to_string(),
+            offset: start,
+        })
+    }
+
+    fn args(&mut self) -> Result<Vec<Arg>, DecodeError> {
+        let count = self.u32()? as usize;
+        (0..count).map(|_| self.arg()).collect()
+    }
+
+    fn arg(&mut self) -> Result<Arg, DecodeError> {
+        let name = self.string()?;
+        let code = self.u8()?;
+        let data_type = type_from_code(code).ok_or_else(|| DecodeError {
+            message: format!("Invalid type code {code} for \"{name}\""),
+            offset: self.offset - 1,
+        })?;
+        let array_len = match self.u32()? {
+            0 => None,
+            len => Some(len as usize),
+        };
+        let count = self.u32()? as usize;
+
+        let mut arg = Arg {
+            name,
+            data_type,
+            array_len,
+            count,
+            value: Value::Integers(Vec::new()),
+        };
+        let len = arg.scalar_count();
+
+        // Guard against allocating huge buffers for corrupt streams.
+        if self.bytes.len() - self.offset < len {
+            return Err(self.error(format!(
+                "Argument \"{}\" declares more values than the stream holds",
+                arg.name
+            )));
+        }
+
+        arg.value = match data_type {
+            Type::Integer => {
+                Value::Integers((0..len).map(|_| self.i32()).collect::<Result<_, _>>()?)
+            }
+            Type::Double | Type::DoubleMatrix => {
+                Value::Doubles((0..len).map(|_| self.f64()).collect::<Result<_, _>>()?)
+            }
+            Type::String => {
+                Value::Strings((0..len).map(|_| self.string()).collect::<Result<_, _>>()?)
+            }
+            _ => Value::Floats((0..len).map(|_| self.f32()).collect::<Result<_, _>>()?),
+        };
+
+        Ok(arg)
+    }
+
+    fn command(&mut self) -> Result<Command, DecodeError> {
+        let opcode = self.u8()?;
+        Ok(match opcode {
+            1 => Command::Create {
+                handle: self.string()?,
+                node_type: self.string()?,
+                args: self.args()?,
+            },
+            2 => Command::Delete {
+                handle: self.string()?,
+                args: self.args()?,
+            },
+            3 => Command::SetAttribute {
+                handle: self.string()?,
+                args: self.args()?,
+            },
+            4 => Command::SetAttributeAtTime {
+                handle: self.string()?,
+                time: self.f64()?,
+                args: self.args()?,
+            },
+            5 => Command::DeleteAttribute {
+                handle: self.string()?,
+                name: self.string()?,
+            },
+            6 => Command::Connect {
+                from: self.string()?,
+                from_attr: self.string()?,
+                to: self.string()?,
+                to_attr: self.string()?,
+                args: self.args()?,
+            },
+            7 => Command::Disconnect {
+                from: self.string()?,
+                from_attr: self.string()?,
+                to: self.string()?,
+                to_attr: self.string()?,
+            },
+            8 => Command::Evaluate { args: self.args()? },
+            9 => Command::RenderControl { args: self.args()? },
+            _ => {
+                self.offset -= 1;
+                return Err(self.error(format!("Unknown opcode {opcode}")));
+            }
+        })
+    }
+}
+
+/// Decodes a binary NSI stream into a list of [`Statement`]s.
+///
+/// The spans of the returned statements hold byte offsets only; line and
+/// column are zero.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Statement>, DecodeError> {
+    if !has_magic(bytes) {
+        return Err(DecodeError {
+            message: "Not a binary NSI stream".to_string(),
+            offset: 0,
+        });
+    }
+
+    let mut decoder = Decoder {
+        bytes,
+        offset: MAGIC.len(),
+    };
+    let mut statements = Vec::new();
+
+    while decoder.offset < bytes.len() {
+        let start = decoder.offset;
+        let command = decoder.command()?;
+        statements.push(Statement {
+            command,
+            span: Span {
+                start,
+                end: decoder.offset,
+                line: 0,
+                column: 0,
+            },
+        });
+    }
+
+    Ok(statements)
+}
+
+fn write_string(writer: &mut impl io::Write, s: &str) -> io::Result<()> {
+    writer.write_all(&(s.len() as u32).to_le_bytes())?;
+    writer.write_all(s.as_bytes())
+}
+
+fn write_args(writer: &mut impl io::Write, args: &[Arg]) -> io::Result<()> {
+    writer.write_all(&(args.len() as u32).to_le_bytes())?;
+
+    for arg in args {
+        write_string(writer, &arg.name)?;
+        writer.write_all(&[type_code(arg.data_type)])?;
+        writer.write_all(&(arg.array_len.unwrap_or(0) as u32).to_le_bytes())?;
+        writer.write_all(&(arg.count as u32).to_le_bytes())?;
+
+        match &arg.value {
+            Value::Integers(v) => v
+                .iter()
+                .try_for_each(|i| writer.write_all(&i.to_le_bytes()))?,
+            Value::Floats(v) => v
+                .iter()
+                .try_for_each(|f| writer.write_all(&f.to_le_bytes()))?,
+            Value::Doubles(v) => v
+                .iter()
+                .try_for_each(|d| writer.write_all(&d.to_le_bytes()))?,
+            Value::Strings(v) => v.iter().try_for_each(|s| write_string(writer, s))?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes commands as a binary NSI stream.
+pub fn write_binary<'a>(
+    writer: &mut impl io::Write,
+    commands: impl IntoIterator<Item = &'a Command>,
+) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+
+    for command in commands {
+        writer.write_all(&[opcode(command)])?;
+
+        match command {
+            Command::Create {
+                handle, node_type, ..
+            } => {
+                write_string(writer, handle)?;
+                write_string(writer, node_type)?;
+            }
+            Command::Delete { handle, .. } | Command::SetAttribute { handle, .. } => {
+                write_string(writer, handle)?
+            }
+            Command::SetAttributeAtTime { handle, time, .. } => {
+                write_string(writer, handle)?;
+                writer.write_all(&time.to_le_bytes())?;
+            }
+            Command::DeleteAttribute { handle, name } => {
+                write_string(writer, handle)?;
+                write_string(writer, name)?;
+            }
+            Command::Connect {
+                from,
+                from_attr,
+                to,
+                to_attr,
+                ..
+            }
+            | Command::Disconnect {
+                from,
+                from_attr,
+                to,
+                to_attr,
+            } => {
+                write_string(writer, from)?;
+                write_string(writer, from_attr)?;
+                write_string(writer, to)?;
+                write_string(writer, to_attr)?;
+            }
+            Command::Evaluate { .. } | Command::RenderControl { .. } => (),
+        }
+
+        if !matches!(
+            command,
+            Command::DeleteAttribute { .. } | Command::Disconnect { .. }
+        ) {
+            write_args(writer, command.args())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Decodes the binary NSI stream `bytes`, read from `path`, that was not
+/// written by rdl.
+///
+/// NSI archives the stream evaluates are inlined, like with `rdl cat`. The
+/// spans of the returned statements refer to the ASCII conversion.
+pub fn decode_with_lib3delight(bytes: &[u8], path: &Path) -> anyhow::Result<Vec<Statement>> {
+    let ascii = convert(bytes, path)?;
+    let source = std::str::from_utf8(&ascii)
+        .map_err(|e| anyhow!("lib3delight wrote an invalid ASCII NSI stream: {e}"))?;
+
+    parse(source).map_err(|e| anyhow!("{e} (in the ASCII conversion)"))
+}
+
+/// Converts the binary NSI stream `bytes`, read from `path`, to ASCII with
+/// lib3delight.
+///
+/// The stream is evaluated from a copy next to `path` so the relative paths of
+/// the archives it inlines resolve the same way.
+fn convert(bytes: &[u8], path: &Path) -> anyhow::Result<Vec<u8>> {
+    let input = copy_path(path, "convert");
+    let output = temp_path("convert");
+    fs::write(&input, bytes)?;
+
+    let result = evaluate_into(&input, &output).and_then(|()| {
+        fs::read(&output).map_err(|e| anyhow!("lib3delight did not write the stream: {e}"))
+    });
+
+    let _ = fs::remove_file(&input);
+    let _ = fs::remove_file(&output);
+
+    result
+}
+
+fn evaluate_into(input: &Path, output: &Path) -> anyhow::Result<()> {
+    let output = output.to_string_lossy();
+    let input = input.to_string_lossy();
+
+    let ctx_args = [
+        nsi::string!("streamfilename", output.as_ref()),
+        nsi::strings!("executeprocedurals", &["apistream"]),
+    ];
+
+    // The stream is flushed when the context is dropped.
+    let ctx = nsi::Context::new(Some(&ctx_args)).ok_or_else(|| {
+        anyhow!("Binary NSI streams not written by rdl require lib3delight to be installed")
+    })?;
+
+    ctx.evaluate(&[
+        nsi::string!("type", "apistream"),
+        nsi::integer!("nostream", true as _),
+        nsi::string!("filename", input.as_ref()),
+    ]);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = r#"Create "mesh1" "mesh" "tag" "string" 1 "hero"
+SetAttribute "mesh1"
+  "nvertices" "int" 1 4
+  "P" "point" 4 [ 0 0 0  1 0 0  1 1 0  0 1 0 ]
+  "uv" "float[2]" 1 [ 0.5 0.25 ]
+  "Cs" "color" 1 [ 1 0.5 0.25 ]
+SetAttributeAtTime "xform" 0.5
+  "transformationmatrix" "doublematrix" 1 [
+    1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1.5 ]
+  "weight" "double" 1 0.125
+Connect "mesh1" "" "xform" "objects" "strength" "double" 1 2.5
+Disconnect "a" "b" "c" "d"
+DeleteAttribute "mesh1" "uv"
+Delete "mesh1" "recursive" "int" 1 1
+Evaluate "filename" "string" 1 "in \"quotes\"\n" "type" "string" 1 "apistream"
+RenderControl "action" "string" 1 "start"
+"#;
+
+    fn commands(statements: Vec<Statement>) -> Vec<Command> {
+        statements
+            .into_iter()
+            .map(|statement| statement.command)
+            .collect()
+    }
+
+    fn encode(commands: &[Command]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_binary(&mut bytes, commands).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn detects_binary() {
+        assert!(!is_binary(SCENE.as_bytes()));
+        assert!(!is_binary(b""));
+        assert!(is_binary(b"\x01\x00\x00\x00Create"));
+
+        assert!(has_magic(&encode(&[])));
+        assert!(!has_magic(b"\x01\x00\x00\x00Create"));
+    }
+
+    #[test]
+    fn round_trips() {
+        let ascii = commands(parse(SCENE).unwrap());
+        let binary = encode(&ascii);
+        assert!(is_binary(&binary));
+
+        let decoded = decode(&binary).unwrap();
+        assert_eq!(ascii, commands(decoded.clone()));
+
+        // Spans cover the encoded commands back to back.
+        assert_eq!(MAGIC.len(), decoded[0].span.start);
+        assert!(decoded
+            .windows(2)
+            .all(|pair| pair[0].span.end == pair[1].span.start));
+        assert_eq!(binary.len(), decoded.last().unwrap().span.end);
+
+        let mut rewritten = Vec::new();
+        write_ascii(&mut rewritten, &ascii).unwrap();
+        assert_eq!(
+            ascii,
+            commands(parse(std::str::from_utf8(&rewritten).unwrap()).unwrap())
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_offset() {
+        let error = |bytes: &[u8]| decode(bytes).unwrap_err().to_string();
+
+        assert_eq!("byte 0: Not a binary NSI stream", error(b"Create"));
+        assert_eq!("byte 5: Unknown opcode 42", error(b"NSI\0\x01\x2a"));
+
+        // The last value, "start", is cut short.
+        let binary = encode(&commands(parse(SCENE).unwrap()));
+        assert_eq!(
+            format!("byte {}: Unexpected end of stream", binary.len() - 5),
+            error(&binary[..binary.len() - 1])
+        );
+
+        // `Evaluate` with one argument named "a" of type code 99.
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([8, 1, 0, 0, 0, 1, 0, 0, 0, b'a', 99]);
+        assert_eq!("byte 15: Invalid type code 99 for \"a\"", error(&bytes));
+    }
+
+    #[test]
+    fn reads_compressed_files() {
+        let path = std::env::temp_dir().join(format!("rdl-binary-{}.nsi.gz", std::process::id()));
+        let ascii = commands(parse(SCENE).unwrap());
+        fs::write(&path, Compression::Gzip.compress(&encode(&ascii)).unwrap()).unwrap();
+
+        let statements = read_file(&path);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(ascii, commands(statements.unwrap()));
+    }
+}
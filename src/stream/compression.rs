@@ -0,0 +1,181 @@
+//! Gzip and zstd compression of NSI streams.
+//!
+//! The format is told from the magic number, not the file name. Both
+//! decoders read streams made of several concatenated members or frames, as
+//! written by appending to a compressed file.
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+use ruzstd::decoding::{FrameDecoder, StreamingDecoder};
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// A compression format of NSI streams.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects the compression of `bytes` from their magic number.
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if bytes.starts_with(ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            Compression::Zstd => Ok(ruzstd::encoding::compress_to_vec(
+                bytes,
+                ruzstd::encoding::CompressionLevel::Fastest,
+            )),
+        }
+    }
+
+    /// Wraps `reader` in a decoder for this compression.
+    pub fn decoder<'a>(self, reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Compression::Zstd => Box::new(ZstdDecoder::new(BufReader::new(reader))?),
+        })
+    }
+
+    pub fn decompress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        self.decoder(bytes)?.read_to_end(&mut output)?;
+
+        Ok(output)
+    }
+}
+
+/// Decodes all frames of a zstd stream; a [`StreamingDecoder`] stops after
+/// the first.
+struct ZstdDecoder<R: BufRead> {
+    /// `None` once the input is exhausted.
+    frame: Option<StreamingDecoder<R, FrameDecoder>>,
+}
+
+impl<R: BufRead> ZstdDecoder<R> {
+    fn new(reader: R) -> io::Result<Self> {
+        Ok(Self {
+            frame: Some(Self::frame(reader)?),
+        })
+    }
+
+    fn frame(reader: R) -> io::Result<StreamingDecoder<R, FrameDecoder>> {
+        StreamingDecoder::new(reader)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+impl<R: BufRead> Read for ZstdDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(frame) = &mut self.frame {
+            let read = frame.read(buf)?;
+            if 0 < read || buf.is_empty() {
+                return Ok(read);
+            }
+
+            // The frame is done; continue with the next one, if any.
+            let mut reader = self.frame.take().map(StreamingDecoder::into_inner).unwrap();
+            if !reader.fill_buf()?.is_empty() {
+                self.frame = Some(Self::frame(reader)?);
+            }
+        }
+
+        Ok(0)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+/// Decompresses `bytes` if they are gzip or zstd compressed.
+pub fn decompress(bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+    match Compression::detect(&bytes) {
+        Some(compression) => compression.decompress(&bytes),
+        None => Ok(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STREAM: &[u8] =
+        b"Create \"mesh1\" \"mesh\"\nSetAttribute \"mesh1\" \"nvertices\" \"int\" 1 3\n";
+
+    #[test]
+    fn detects_compression() {
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let compressed = compression.compress(STREAM).unwrap();
+            assert_eq!(Some(compression), Compression::detect(&compressed));
+        }
+
+        assert_eq!(None, Compression::detect(STREAM));
+        assert_eq!(None, Compression::detect(b"NSI\0\x01"));
+        assert_eq!(None, Compression::detect(&[0x1f]));
+        assert_eq!(None, Compression::detect(&[]));
+    }
+
+    #[test]
+    fn round_trips() {
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let compressed = compression.compress(STREAM).unwrap();
+            assert_ne!(STREAM, compressed.as_slice());
+
+            assert_eq!(STREAM, compression.decompress(&compressed).unwrap());
+            assert_eq!(STREAM, decompress(compressed).unwrap());
+        }
+
+        assert_eq!(STREAM, decompress(STREAM.to_vec()).unwrap());
+    }
+
+    #[test]
+    fn reads_all_frames() {
+        let (first, second) = STREAM.split_at(10);
+
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let mut compressed = compression.compress(first).unwrap();
+            compressed.extend(compression.compress(second).unwrap());
+            compressed.extend(compression.compress(b"").unwrap());
+
+            let mut output = Vec::new();
+            compression
+                .decoder(compressed.as_slice())
+                .unwrap()
+                .read_to_end(&mut output)
+                .unwrap();
+            assert_eq!(STREAM, output, "{compression}");
+        }
+    }
+
+    #[test]
+    fn reports_corrupt_streams() {
+        let mut compressed = Compression::Zstd.compress(STREAM).unwrap();
+        compressed.truncate(compressed.len() / 2);
+
+        assert!(Compression::Zstd.decompress(&compressed).is_err());
+        assert!(Compression::Zstd.decompress(b"garbage").is_err());
+    }
+}
@@ -0,0 +1,316 @@
+//! Pure Rust representation of NSI streams.
+//!
+//! This allows inspecting and transforming NSI streams on machines that do
+//! not have 3Delight installed.
+use log::warn;
+use std::{
+    env, fmt,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+mod ascii;
+pub use ascii::*;
+
+mod binary;
+pub use binary::*;
+
+mod compression;
+pub use compression::*;
+
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Location of a [`Command`] in the stream it was read from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offset of the first character of the command.
+    pub start: usize,
+    /// Byte offset one past the last character of the command.
+    pub end: usize,
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column number.
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The type of an NSI argument.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Type {
+    Float,
+    Double,
+    Integer,
+    String,
+    Color,
+    Point,
+    Vector,
+    Normal,
+    Matrix,
+    DoubleMatrix,
+}
+
+impl Type {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "float" => Type::Float,
+            "double" => Type::Double,
+            "int" | "integer" => Type::Integer,
+            "string" => Type::String,
+            "color" => Type::Color,
+            "point" => Type::Point,
+            "vector" => Type::Vector,
+            "normal" => Type::Normal,
+            "matrix" => Type::Matrix,
+            "doublematrix" => Type::DoubleMatrix,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Type::Float => "float",
+            Type::Double => "double",
+            Type::Integer => "int",
+            Type::String => "string",
+            Type::Color => "color",
+            Type::Point => "point",
+            Type::Vector => "vector",
+            Type::Normal => "normal",
+            Type::Matrix => "matrix",
+            Type::DoubleMatrix => "doublematrix",
+        }
+    }
+
+    /// Number of scalars a single element of this type consists of.
+    pub fn components(&self) -> usize {
+        match self {
+            Type::Float | Type::Double | Type::Integer | Type::String => 1,
+            Type::Color | Type::Point | Type::Vector | Type::Normal => 3,
+            Type::Matrix | Type::DoubleMatrix => 16,
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The scalar data of an [`Arg`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Integers(Vec<i32>),
+    Floats(Vec<f32>),
+    Doubles(Vec<f64>),
+    Strings(Vec<String>),
+}
+
+impl Value {
+    /// Number of scalars stored.
+    pub fn len(&self) -> usize {
+        match self {
+            Value::Integers(v) => v.len(),
+            Value::Floats(v) => v.len(),
+            Value::Doubles(v) => v.len(),
+            Value::Strings(v) => v.len(),
+        }
+    }
+}
+
+/// A named, typed argument of a [`Command`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Arg {
+    pub name: String,
+    pub data_type: Type,
+    /// Set if the argument was declared as `type[n]`.
+    pub array_len: Option<usize>,
+    /// Number of elements.
+    pub count: usize,
+    pub value: Value,
+}
+
+impl Arg {
+    /// Number of scalars the argument's declaration implies.
+    pub fn scalar_count(&self) -> usize {
+        self.count * self.data_type.components() * self.array_len.unwrap_or(1)
+    }
+
+    /// Returns the first string if this is a `string` argument.
+    pub fn as_str(&self) -> Option<&str> {
+        self.strings().first().map(|s| s.as_str())
+    }
+
+    /// Returns all strings if this is a `string` argument.
+    pub fn strings(&self) -> &[String] {
+        match &self.value {
+            Value::Strings(s) => s,
+            _ => &[],
+        }
+    }
+}
+
+/// A single NSI API call.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Create {
+        handle: String,
+        node_type: String,
+        args: Vec<Arg>,
+    },
+    Delete {
+        handle: String,
+        args: Vec<Arg>,
+    },
+    SetAttribute {
+        handle: String,
+        args: Vec<Arg>,
+    },
+    SetAttributeAtTime {
+        handle: String,
+        time: f64,
+        args: Vec<Arg>,
+    },
+    DeleteAttribute {
+        handle: String,
+        name: String,
+    },
+    Connect {
+        from: String,
+        from_attr: String,
+        to: String,
+        to_attr: String,
+        args: Vec<Arg>,
+    },
+    Disconnect {
+        from: String,
+        from_attr: String,
+        to: String,
+        to_attr: String,
+    },
+    Evaluate {
+        args: Vec<Arg>,
+    },
+    RenderControl {
+        args: Vec<Arg>,
+    },
+}
+
+impl Command {
+    /// The name of the command as used in ASCII NSI streams.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Create { .. } => "Create",
+            Command::Delete { .. } => "Delete",
+            Command::SetAttribute { .. } => "SetAttribute",
+            Command::SetAttributeAtTime { .. } => "SetAttributeAtTime",
+            Command::DeleteAttribute { .. } => "DeleteAttribute",
+            Command::Connect { .. } => "Connect",
+            Command::Disconnect { .. } => "Disconnect",
+            Command::Evaluate { .. } => "Evaluate",
+            Command::RenderControl { .. } => "RenderControl",
+        }
+    }
+
+    /// The arguments of the command, if it takes any.
+    pub fn args(&self) -> &[Arg] {
+        match self {
+            Command::Create { args, .. }
+            | Command::Delete { args, .. }
+            | Command::SetAttribute { args, .. }
+            | Command::SetAttributeAtTime { args, .. }
+            | Command::Connect { args, .. }
+            | Command::Evaluate { args }
+            | Command::RenderControl { args } => args,
+            Command::DeleteAttribute { .. } | Command::Disconnect { .. } => &[],
+        }
+    }
+
+    pub fn args_mut(&mut self) -> Option<&mut Vec<Arg>> {
+        match self {
+            Command::Create { args, .. }
+            | Command::Delete { args, .. }
+            | Command::SetAttribute { args, .. }
+            | Command::SetAttributeAtTime { args, .. }
+            | Command::Connect { args, .. }
+            | Command::Evaluate { args }
+            | Command::RenderControl { args } => Some(args),
+            Command::DeleteAttribute { .. } | Command::Disconnect { .. } => None,
+        }
+    }
+}
+
+/// A [`Command`] together with its location in the source stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement {
+    pub command: Command,
+    pub span: Span,
+}
+
+/// Reads an NSI stream from disk, decompressing it if it is gzip or zstd
+/// compressed.
+pub fn read_bytes(path: &Path) -> anyhow::Result<Vec<u8>> {
+    fs::read(path)
+        .and_then(decompress)
+        .map_err(|e| anyhow::anyhow!("Could not read '{}': {e}", path.display()))
+}
+
+/// Reads and parses an ASCII or binary NSI stream from disk.
+///
+/// Compressed streams are decompressed first.
+pub fn read_file(path: &Path) -> anyhow::Result<Vec<Statement>> {
+    let bytes = read_bytes(path)?;
+
+    if has_magic(&bytes) {
+        decode(&bytes).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))
+    } else if is_binary(&bytes) {
+        decode_with_lib3delight(&bytes, path)
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))
+    } else {
+        let source = std::str::from_utf8(&bytes)
+            .map_err(|e| anyhow::anyhow!("'{}' is not a valid NSI stream: {e}", path.display()))?;
+
+        parse(source).map_err(|e| anyhow::anyhow!("{}:{e}", path.display()))
+    }
+}
+
+/// Returns a unique path for a temporary copy of the stream at `original`.
+///
+/// The copy is hidden next to the original so relative paths in it resolve
+/// the same way. If that folder is not writable, the temp folder is used.
+pub fn copy_path(original: &Path, purpose: &str) -> PathBuf {
+    let name = original.file_name().unwrap_or_default().to_string_lossy();
+    let path = original.with_file_name(format!(
+        ".{name}.rdl-{purpose}-{}-{}.nsi",
+        process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    match File::create(&path) {
+        Ok(_) => path,
+        Err(error) => {
+            warn!(
+                "Could not write next to '{}': {error}; relative paths in it resolve \
+                 against the temp folder",
+                original.display()
+            );
+            temp_path(purpose)
+        }
+    }
+}
+
+/// Returns a unique path for a temporary file.
+pub fn temp_path(purpose: &str) -> PathBuf {
+    env::temp_dir().join(format!(
+        "rdl-{purpose}-{}-{}.nsi",
+        process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
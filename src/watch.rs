@@ -1,13 +1,127 @@
 use crate::Result;
-use crate::{evaluate_file, Watch};
-use log::info;
+use crate::{evaluate_file, Remap, Watch};
+use anyhow::anyhow;
+use log::{debug, error, info, warn};
 use notify::{
-    Config, EventKind::Create, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher, WatcherKind,
+    event::{ModifyKind, RenameMode},
+    Config, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher, WatcherKind,
 };
-use std::{path::Path, sync::mpsc, time::Duration};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Name of the file listing patterns of files not to render in a folder.
+const IGNORE_FILE: &str = ".rdlignore";
+
+/// How often pending files are checked.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Name prefixes of temporary or partially written files.
+const TEMP_PREFIXES: [&str; 3] = [".", "~", "#"];
+/// Name suffixes of temporary or partially written files.
+const TEMP_SUFFIXES: [&str; 8] = [
+    "~",
+    ".tmp",
+    ".temp",
+    ".part",
+    ".partial",
+    ".crdownload",
+    ".download",
+    ".swp",
+];
+
+/// Size and modification time of a file.
+type Stamp = (u64, Option<SystemTime>);
+
+/// A pattern of a `.rdlignore` file.
+struct IgnoreRule {
+    pattern: glob::Pattern,
+    /// Matched against the path below the `.rdlignore` instead of names.
+    anchored: bool,
+    /// Only matches folders, i.e. ends in `/`.
+    folder_only: bool,
+    /// Re-includes what earlier rules ignored, i.e. starts with `!`.
+    negated: bool,
+}
+
+/// A file that changed but was not rendered yet.
+struct Pending {
+    stamp: Stamp,
+    /// When `stamp` was last seen to change.
+    changed: Instant,
+}
+
+/// Decides which files in the watched folders are rendered.
+struct Filter {
+    /// The watched folders, canonicalized to match event paths.
+    roots: Vec<PathBuf>,
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+}
+
+impl Filter {
+    fn new(args: &Watch) -> Result<Self> {
+        let patterns = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    glob::Pattern::new(pattern)
+                        .map_err(|e| anyhow!("Invalid glob pattern '{pattern}': {e}"))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+
+        Ok(Self {
+            roots: args
+                .folder
+                .iter()
+                .map(|folder| fs::canonicalize(folder).unwrap_or_else(|_| PathBuf::from(folder)))
+                .collect(),
+            include: patterns(&args.include)?,
+            exclude: patterns(&args.exclude)?,
+        })
+    }
+
+    /// Returns `true` if `path` matches `--include`, and neither `--exclude`
+    /// nor a `.rdlignore` on the way from its watched folder.
+    fn accepts(&self, path: &Path) -> bool {
+        // The innermost watched folder `path` is in.
+        let root = self
+            .roots
+            .iter()
+            .filter(|root| path.starts_with(root))
+            .max_by_key(|root| root.components().count());
+
+        let (root, relative) = match root {
+            Some(root) => (root.as_path(), path.strip_prefix(root).unwrap_or(path)),
+            None => (path.parent().unwrap_or(Path::new("")), path),
+        };
+        let relative = relative
+            .iter()
+            .filter_map(OsStr::to_str)
+            .collect::<Vec<_>>();
+
+        self.include
+            .iter()
+            .any(|pattern| matches_file(pattern, &relative))
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| matches_file(pattern, &relative))
+            && !is_ignored(root, &relative)
+    }
+}
 
 // example of detecting the recommended watcher kind
 pub fn watch(args: Watch) -> Result<()> {
+    let remap = Remap::new(&args.input.remap, args.input.remap_file.as_deref())?;
+    let filter = Filter::new(&args)?;
+
     let (tx, rx) = mpsc::channel();
     // This example is a little bit misleading as you can just create one Config and use it for all watchers.
     // That way the pollwatcher specific stuff is still configured, if it should be used.
@@ -37,24 +151,254 @@ pub fn watch(args: Watch) -> Result<()> {
 
     info!("Watching for files to render in {:?}…", args.folder);
 
-    while let Ok(result) = rx.recv() {
-        if let Ok(event) = result {
-            if let Create(_) = event.kind {
-                event.paths.iter().for_each(|path| {
-                    let file = path.as_path().to_str().unwrap();
-                    info!("Rendering '{file}'");
-                    render(file, &args);
-                })
+    let settle = Duration::try_from_secs_f64(args.settle.max(0.0)).unwrap_or(Duration::MAX);
+    let mut pending = HashMap::<PathBuf, Pending>::new();
+    // Files as they were when last rendered, to ignore events that did not
+    // change them.
+    let mut rendered = HashMap::<PathBuf, Stamp>::new();
+
+    loop {
+        match rx.recv_timeout(TICK) {
+            Ok(Ok(event)) => {
+                for path in changed_paths(&event) {
+                    let path = sentinel_target(path, args.sentinel.as_deref());
+
+                    if is_temporary(&path) {
+                        debug!("Ignoring temporary file '{}'", path.display());
+                        continue;
+                    }
+
+                    if !filter.accepts(&path) {
+                        debug!("Ignoring filtered file '{}'", path.display());
+                        continue;
+                    }
+
+                    if let Some(stamp) = stamp(&path) {
+                        pending.entry(path).or_insert(Pending {
+                            stamp,
+                            changed: Instant::now(),
+                        });
+                    }
+                }
             }
-        } else {
-            println!("Error");
+            Ok(Err(error)) => error!("{error}"),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        let ready = take_ready(&mut pending, args.sentinel.as_deref(), settle);
+
+        for (path, stamp) in ready {
+            if Some(&stamp) == rendered.get(&path) {
+                debug!("'{}' did not change since it was rendered", path.display());
+                continue;
+            }
+
+            let Some(file) = path.to_str() else {
+                error!("'{}' is not a valid UTF-8 path", path.display());
+                continue;
+            };
+            render(file, &args, remap.as_ref());
+            rendered.insert(path, stamp);
         }
     }
 
     Ok(())
 }
 
-fn render(file_name: &str, args: &Watch) {
+/// Returns the file a change of `path` is about: the file a sentinel
+/// belongs to, or else `path` itself.
+fn sentinel_target(path: &Path, sentinel: Option<&str>) -> PathBuf {
+    sentinel
+        .and_then(|sentinel| path.to_str()?.strip_suffix(sentinel))
+        .map_or_else(|| path.to_path_buf(), PathBuf::from)
+}
+
+/// Removes the files from `pending` that are ready to render and returns
+/// them with their stamps.
+///
+/// A file is ready once its `sentinel` exists or, without one, once its
+/// stamp did not change for `settle`. Files that vanished are dropped.
+fn take_ready(
+    pending: &mut HashMap<PathBuf, Pending>,
+    sentinel: Option<&str>,
+    settle: Duration,
+) -> Vec<(PathBuf, Stamp)> {
+    let mut ready = Vec::new();
+
+    pending.retain(|path, file| {
+        // Deleted or moved away.
+        let Some(stamp) = stamp(path) else {
+            return false;
+        };
+
+        if stamp != file.stamp {
+            file.stamp = stamp;
+            file.changed = Instant::now();
+            return true;
+        }
+
+        let is_ready = match sentinel {
+            Some(sentinel) => {
+                let mut name = path.clone().into_os_string();
+                name.push(sentinel);
+                Path::new(&name).exists()
+            }
+            None => settle <= file.changed.elapsed(),
+        };
+
+        if is_ready {
+            ready.push((path.clone(), stamp));
+        }
+        !is_ready
+    });
+
+    ready
+}
+
+/// Returns the paths of files that `event` created, wrote to or moved into a
+/// watched folder.
+fn changed_paths(event: &Event) -> Vec<&PathBuf> {
+    let paths = match event.kind {
+        EventKind::Create(_)
+        | EventKind::Modify(ModifyKind::Any)
+        | EventKind::Modify(ModifyKind::Data(_))
+        | EventKind::Modify(ModifyKind::Other)
+        | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        | EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => event.paths.iter().collect(),
+        // `paths` holds the old and the new name.
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            event.paths.iter().skip(1).collect()
+        }
+        _ => Vec::new(),
+    };
+
+    paths.into_iter().filter(|path| path.is_file()).collect()
+}
+
+/// Returns `true` if `path` names a temporary or partially written file, e.g.
+/// `.foo.nsi`, `foo.nsi~` or `foo.nsi.part`.
+fn is_temporary(path: &Path) -> bool {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .map(str::to_ascii_lowercase)
+        .is_some_and(|name| {
+            TEMP_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+                || TEMP_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+        })
+}
+
+/// Options for matching `--include`, `--exclude` and `.rdlignore` patterns.
+const MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Matches the file at `relative`, given as path components, against
+/// `pattern`.
+///
+/// Patterns containing a `/` are matched against the whole path, others
+/// against the file name.
+fn matches_file(pattern: &glob::Pattern, relative: &[&str]) -> bool {
+    if pattern.as_str().contains('/') {
+        pattern.matches_with(&relative.join("/"), MATCH_OPTIONS)
+    } else {
+        relative
+            .last()
+            .is_some_and(|name| pattern.matches_with(name, MATCH_OPTIONS))
+    }
+}
+
+/// Returns `true` if the `.rdlignore` files in `root` and the folders between
+/// it and the file at `relative` ignore the file or one of its folders.
+///
+/// Like with `.gitignore`, the last matching rule wins and the files of
+/// deeper folders are read last.
+fn is_ignored(root: &Path, relative: &[&str]) -> bool {
+    let mut folder = root.to_path_buf();
+    let mut ignored = false;
+
+    for depth in 0..relative.len() {
+        let below = &relative[depth..];
+
+        for rule in read_ignore_file(&folder.join(IGNORE_FILE)) {
+            if rule.matches(below) {
+                ignored = !rule.negated;
+            }
+        }
+
+        folder.push(relative[depth]);
+    }
+
+    ignored
+}
+
+impl IgnoreRule {
+    /// Parses a line of a `.rdlignore` file.
+    fn new(line: &str) -> std::result::Result<Self, glob::PatternError> {
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let folder_only = line.ends_with('/');
+        let line = line.trim_end_matches('/');
+
+        Ok(Self {
+            pattern: glob::Pattern::new(line.trim_start_matches('/'))?,
+            anchored: line.contains('/'),
+            folder_only,
+            negated,
+        })
+    }
+
+    /// Matches the file at `below`, given as path components, and its
+    /// folders.
+    fn matches(&self, below: &[&str]) -> bool {
+        // Leave out the file itself for rules only matching folders.
+        let len = below.len() - self.folder_only as usize;
+
+        if self.anchored {
+            (1..=len).any(|end| {
+                self.pattern
+                    .matches_with(&below[..end].join("/"), MATCH_OPTIONS)
+            })
+        } else {
+            below[..len]
+                .iter()
+                .any(|name| self.pattern.matches_with(name, MATCH_OPTIONS))
+        }
+    }
+}
+
+/// Reads the rules of a `.rdlignore` file.
+///
+/// A missing file has no rules.
+fn read_ignore_file(path: &Path) -> Vec<IgnoreRule> {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            IgnoreRule::new(line)
+                .map_err(|e| warn!("Invalid pattern '{line}' in '{}': {e}", path.display()))
+                .ok()
+        })
+        .collect()
+}
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = fs::metadata(path).ok()?;
+    metadata
+        .is_file()
+        .then(|| (metadata.len(), metadata.modified().ok()))
+}
+
+fn render(file_name: &str, args: &Watch, remap: Option<&Remap>) {
     let ctx = {
         let mut ctx_args = Vec::with_capacity(2);
 
@@ -67,7 +411,302 @@ fn render(file_name: &str, args: &Watch) {
         nsi::Context::new(Some(&ctx_args)).unwrap()
     };
 
-    evaluate_file(&ctx, file_name, false);
+    match evaluate_file(&ctx, file_name, args.input.input_format.as_deref(), remap) {
+        Ok(_remapped) => ctx.render_control(nsi::Action::Wait, None),
+        Err(error) => error!("{error}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cli, Command};
+    use clap::Parser;
+    use notify::event::{AccessKind, CreateKind, DataChange, RemoveKind};
+    use std::{env, process};
+
+    struct Folder(PathBuf);
+
+    impl Folder {
+        fn new(name: &str) -> Self {
+            let folder = env::temp_dir().join(format!("rdl-{name}-{}", process::id()));
+            fs::create_dir_all(&folder).unwrap();
+            Self(folder)
+        }
+
+        /// Writes a file below the folder and returns its path.
+        fn write(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for Folder {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn pending(path: &Path) -> HashMap<PathBuf, Pending> {
+        HashMap::from([(
+            path.to_path_buf(),
+            Pending {
+                stamp: stamp(path).unwrap(),
+                changed: Instant::now(),
+            },
+        )])
+    }
+
+    #[test]
+    fn waits_for_files_to_settle() {
+        let folder = Folder::new("watch-settle");
+        let file = folder.write("a.nsi", "Create");
+
+        let mut files = pending(&file);
+        assert!(take_ready(&mut files, None, Duration::from_secs(60)).is_empty());
+        assert_eq!(1, files.len());
+
+        // Still being written.
+        fs::write(&file, "Create \"a\" \"mesh\"").unwrap();
+        assert!(take_ready(&mut files, None, Duration::ZERO).is_empty());
+        assert_eq!(stamp(&file), Some(files[&file].stamp));
+
+        assert_eq!(
+            vec![(file.clone(), stamp(&file).unwrap())],
+            take_ready(&mut files, None, Duration::ZERO)
+        );
+        assert!(files.is_empty());
+
+        // Deleted before it settled.
+        let mut files = pending(&file);
+        fs::remove_file(&file).unwrap();
+        assert!(take_ready(&mut files, None, Duration::ZERO).is_empty());
+        assert!(files.is_empty());
+    }
+
+    #[test]
+    fn waits_for_sentinels() {
+        let folder = Folder::new("watch-sentinel");
+        let file = folder.write("a.nsi", "Create");
+
+        let mut files = pending(&file);
+        assert!(take_ready(&mut files, Some(".done"), Duration::ZERO).is_empty());
+
+        folder.write("a.nsi.done", "");
+        assert_eq!(
+            1,
+            take_ready(&mut files, Some(".done"), Duration::ZERO).len()
+        );
+
+        assert_eq!(
+            PathBuf::from("shots/foo.nsi"),
+            sentinel_target(Path::new("shots/foo.nsi.done"), Some(".done"))
+        );
+        assert_eq!(
+            PathBuf::from("shots/foo.nsi"),
+            sentinel_target(Path::new("shots/foo.nsi"), Some(".done"))
+        );
+        assert_eq!(
+            PathBuf::from("shots/foo.nsi.done"),
+            sentinel_target(Path::new("shots/foo.nsi.done"), None)
+        );
+    }
 
-    ctx.render_control(nsi::Action::Wait, None);
+    #[test]
+    fn finds_changed_files() {
+        let folder = Folder::new("watch-events");
+        let old = folder.0.join("old.nsi");
+        let new = folder.write("new.nsi", "");
+        let event = |kind, paths: &[&PathBuf]| {
+            paths.iter().fold(Event::new(kind), |event, path| {
+                event.add_path(path.to_path_buf())
+            })
+        };
+
+        for kind in [
+            EventKind::Create(CreateKind::File),
+            EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+        ] {
+            assert_eq!(vec![&new], changed_paths(&event(kind, &[&new])));
+        }
+        assert_eq!(
+            vec![&new],
+            changed_paths(&event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &[&old, &new]
+            ))
+        );
+
+        for kind in [
+            EventKind::Remove(RemoveKind::File),
+            EventKind::Access(AccessKind::Read),
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+        ] {
+            assert!(changed_paths(&event(kind, &[&new])).is_empty());
+        }
+        // Folders and files that are gone are left out.
+        assert!(changed_paths(&event(
+            EventKind::Create(CreateKind::Any),
+            &[&folder.0, &old]
+        ))
+        .is_empty());
+    }
+
+    #[test]
+    fn ignores_temporary_files() {
+        for name in [
+            ".a.nsi",
+            ".a.nsi.swp",
+            "a.nsi~",
+            "~a.nsi",
+            "#a.nsi#",
+            "a.nsi.TMP",
+            "a.nsi.part",
+            "a.nsi.crdownload",
+            ".a.nsi.rdl-remap-123-0.nsi",
+        ] {
+            assert!(is_temporary(&Path::new("shots").join(name)), "{name}");
+        }
+
+        for name in ["a.nsi", "a.nsi.gz", "a.lua", "tmp.nsi"] {
+            assert!(!is_temporary(&Path::new(".shots").join(name)), "{name}");
+        }
+    }
+
+    fn pattern(pattern: &str) -> glob::Pattern {
+        glob::Pattern::new(pattern).unwrap()
+    }
+
+    #[test]
+    fn matches_names_and_paths() {
+        assert!(matches_file(&pattern("*.nsi"), &["a.nsi"]));
+        assert!(matches_file(&pattern("*.nsi"), &["shots", "a.NSI"]));
+        assert!(!matches_file(&pattern("*.nsi"), &["a.nsi.gz"]));
+        assert!(!matches_file(&pattern("*.nsi"), &[]));
+
+        // Patterns with a `/` match the whole path.
+        assert!(matches_file(&pattern("shots/*.nsi"), &["shots", "a.nsi"]));
+        assert!(!matches_file(&pattern("shots/*.nsi"), &["a.nsi"]));
+        assert!(!matches_file(
+            &pattern("shots/*.nsi"),
+            &["shots", "sub", "a.nsi"]
+        ));
+        assert!(matches_file(
+            &pattern("shots/**/*.nsi"),
+            &["shots", "sub", "a.nsi"]
+        ));
+    }
+
+    #[test]
+    fn parses_ignore_rules() {
+        let rule = IgnoreRule::new("*.exr").unwrap();
+        assert_eq!(
+            (false, false, false),
+            (rule.anchored, rule.folder_only, rule.negated)
+        );
+        assert!(rule.matches(&["a.exr"]));
+        assert!(rule.matches(&["renders", "a.exr"]));
+        assert!(!rule.matches(&["a.nsi"]));
+
+        // Unanchored rules also match folder names at any depth.
+        let rule = IgnoreRule::new("cache").unwrap();
+        assert!(rule.matches(&["cache"]));
+        assert!(rule.matches(&["shots", "cache", "a.nsi"]));
+
+        let rule = IgnoreRule::new("cache/").unwrap();
+        assert_eq!((false, true), (rule.anchored, rule.folder_only));
+        assert!(!rule.matches(&["cache"]));
+        assert!(rule.matches(&["shots", "cache", "a.nsi"]));
+
+        // Anchored rules match the path below the `.rdlignore`.
+        for line in ["/old", "shots/old", "shots/old/"] {
+            assert!(IgnoreRule::new(line).unwrap().anchored, "{line}");
+        }
+        let rule = IgnoreRule::new("/old").unwrap();
+        assert!(rule.matches(&["old", "a.nsi"]));
+        assert!(!rule.matches(&["shots", "old", "a.nsi"]));
+        let rule = IgnoreRule::new("shots/*.nsi").unwrap();
+        assert!(rule.matches(&["shots", "a.nsi"]));
+        assert!(!rule.matches(&["shots", "sub", "a.nsi"]));
+
+        let rule = IgnoreRule::new("!keep.nsi").unwrap();
+        assert!(rule.negated);
+        assert!(rule.matches(&["keep.nsi"]));
+
+        assert!(IgnoreRule::new("[").is_err());
+    }
+
+    #[test]
+    fn reads_nested_ignore_files() {
+        let folder = Folder::new("watch-ignore");
+        folder.write(
+            IGNORE_FILE,
+            "# Comment\n\ntest_*.nsi\n!test_keep.nsi\n/old/\ncache/\n[\n",
+        );
+        folder.write("shots/.rdlignore", "!test_*.nsi\nwip.nsi\n");
+        let ignored = |path: &str| is_ignored(&folder.0, &path.split('/').collect::<Vec<_>>());
+
+        assert!(ignored("test_a.nsi"));
+        assert!(!ignored("test_keep.nsi"));
+        assert!(!ignored("a.nsi"));
+        assert!(ignored("old/a.nsi"));
+        assert!(!ignored("shots/old/a.nsi"));
+        assert!(ignored("cache/a.nsi"));
+        assert!(ignored("shots/cache/a.nsi"));
+
+        // Deeper files win.
+        assert!(!ignored("shots/test_a.nsi"));
+        assert!(ignored("shots/wip.nsi"));
+        assert!(ignored("shots/sub/wip.nsi"));
+        assert!(!ignored("wip.nsi"));
+    }
+
+    /// Parses `rdl watch` with `args`.
+    fn watch_args(args: &[&str]) -> Watch {
+        let Command::Watch(args) = Cli::try_parse_from(["rdl", "watch"].iter().chain(args))
+            .unwrap()
+            .command
+        else {
+            panic!("not a watch command");
+        };
+        args
+    }
+
+    #[test]
+    fn filters_files() {
+        let folder = Folder::new("watch-filter");
+        folder.write(IGNORE_FILE, "ignored.nsi\n");
+        let root = fs::canonicalize(&folder.0).unwrap();
+        let path = folder.0.to_str().unwrap();
+
+        let filter = Filter::new(&watch_args(&[path])).unwrap();
+        for (name, accepted) in [
+            ("a.nsi", true),
+            ("a.nsi.gz", true),
+            ("a.lua", true),
+            ("sub/a.nsi", true),
+            ("a.exr", false),
+            (".DS_Store", false),
+            ("ignored.nsi", false),
+        ] {
+            assert_eq!(accepted, filter.accepts(&root.join(name)), "{name}");
+        }
+
+        let filter = Filter::new(&watch_args(&[
+            "--include",
+            "*.lua",
+            "--exclude",
+            "sub/*",
+            path,
+        ]))
+        .unwrap();
+        assert!(filter.accepts(&root.join("a.lua")));
+        assert!(!filter.accepts(&root.join("a.nsi")));
+        assert!(!filter.accepts(&root.join("sub/a.lua")));
+
+        assert!(Filter::new(&watch_args(&["--include", "[", path])).is_err());
+    }
 }